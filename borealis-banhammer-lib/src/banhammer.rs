@@ -7,19 +7,41 @@
 //! - leak
 //! - overflow
 //! - remove
+//!
 //! Tge "fill" is always >= 0
 //!
 //! Bucket name contains fields: Identiti + IdentityVAlie + ErrorKind
+use crate::bans::{BanConfig, BanRegistry};
 use crate::buckets::{
     BucketConfig, BucketErrorKind, BucketIdentity, BucketName, BucketNameValue,
-    BucketPriorityQueue, LeakyBucket,
+    BucketPriorityQueue, BucketValue, LeakyBucket,
+};
+use crate::cardinality::{Cardinality, CardinalityConfig};
+use crate::clock::{EventClock, EventTimeConfig, SharedClock, SystemClock};
+use crate::contracts::{HotContractConfig, HotContracts};
+use crate::de::{
+    deserialize_duration, serialize_duration, RelayerMessage, Token, TransactionError,
 };
-use crate::de::{RelayerMessage, TransactionError};
+use crate::events::{correlate, BanEvent, HotContractEvent, UnbanEvent};
+use crate::exemptions::{Exempt, Exemptions};
+use crate::levels::{requests_per_second, Level, LevelConfig};
+use crate::methods::MethodPolicies;
+use crate::nonces::{NonceConfig, NonceGap, NonceTracker};
+use crate::ratio::{FailureRatio, FailureRatioConfig};
+use crate::reputation::{Reputation, ReputationConfig};
+use crate::rules::{default_rules, Rule, RuleInput};
+use crate::stats::{Counter, Gauge, Histogram, LiveMetrics, SharedMetrics};
+use crate::subnet::SubnetConfig;
+use crate::tiers::{TokenRegistry, TokenTier, TokenTiersConfig};
+use ethereum_types::Address;
 use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
 use std::collections::HashMap;
-use std::time::{Duration, Instant};
+use std::net::IpAddr;
+use std::time::Duration;
 
-const NEAR_GAS_COUNTER: u64 = 202651902028573;
+/// Estimated NEAR gas per transaction, used if relayer doesn't provide it
+const NEAR_GAS_ESTIMATE: u64 = 202651902028573;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct LeakyBucketConfig {
@@ -28,6 +50,13 @@ pub struct LeakyBucketConfig {
     pub bucket: BucketConfig,
 }
 
+/// Live buckets cap of identity kind
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BucketLimit {
+    pub identity: BucketIdentity,
+    pub max_buckets: usize,
+}
+
 /// Banhammer configs
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Config {
@@ -35,8 +64,119 @@ pub struct Config {
     pub max_gas_threshold: u64,
     pub revert_threshold: u64,
     pub excessive_gas_threshold: u64,
+    /// EVM gas threshold in Mgas, EVM gas buckets are disabled if not set
+    #[serde(default)]
+    pub excessive_evm_gas_threshold: Option<u64>,
+    /// NEAR gas used for transaction if relayer sends 0
+    #[serde(default = "default_near_gas_estimate")]
+    pub near_gas_estimate: u64,
+    /// Thresholds multiplier for messages with token, used if token has no tier
     pub token_multiplier: u64,
     pub leaky_buckets: Vec<LeakyBucketConfig>,
+    /// Ban duration in seconds for the first offence
+    #[serde(
+        default = "default_ban_duration",
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub ban_duration: Duration,
+    /// Banhammer instance identifier for ban events
+    #[serde(default = "default_instance_id")]
+    pub instance_id: String,
+    /// Ban lifecycle: expiry and repeat offenders escalation
+    #[serde(default)]
+    pub bans: BanConfig,
+    /// Failed transactions share rule, disabled if not set
+    #[serde(default)]
+    pub failure_ratio: Option<FailureRatioConfig>,
+    /// Rules filling leaky buckets, built-in rules are used if not set
+    #[serde(default = "default_rules")]
+    pub rules: Vec<Rule>,
+    /// Exempted IP ranges, addresses and tokens
+    #[serde(default)]
+    pub exemptions: Exemptions,
+    /// Client IP subnet buckets, disabled if not set.
+    /// Subnet thresholds are set by bucket `overflow_size`
+    #[serde(default)]
+    pub subnets: Option<SubnetConfig>,
+    /// Distinct sender addresses rule for client IPs
+    /// and tokens, disabled if not set
+    #[serde(default)]
+    pub cardinality: Option<CardinalityConfig>,
+    /// Target contract buckets overflow handling
+    #[serde(default)]
+    pub hot_contracts: HotContractConfig,
+    /// Incorrect nonce classification by last successful
+    /// nonce of address, disabled if not set
+    #[serde(default)]
+    pub nonces: Option<NonceConfig>,
+    /// RPC method policies, all methods are evaluated
+    /// with common buckets if not set
+    #[serde(default)]
+    pub methods: MethodPolicies,
+    /// Token tiers of token registry, `token_multiplier`
+    /// is used for all tokens if not set
+    #[serde(default)]
+    pub token_tiers: Option<TokenTiersConfig>,
+    /// Decaying reputation score of client IPs, addresses
+    /// and tokens, disabled if not set
+    #[serde(default)]
+    pub reputation: Option<ReputationConfig>,
+    /// Live buckets caps per identity kind, least recently
+    /// updated buckets with the lowest fill are evicted over
+    /// the cap. Reputation scores, failure ratio counters,
+    /// distinct addresses, last nonces and bans history of
+    /// identity kind values are capped the same on tick, least
+    /// recently updated first, active bans are kept. Buckets and
+    /// values are only removed by retention if not set
+    #[serde(default)]
+    pub bucket_limits: Vec<BucketLimit>,
+    /// Event time of relayer messages timestamps instead of wall
+    /// clock, applied when Banhammer is created. Wall clock if not set
+    #[serde(default)]
+    pub event_time: Option<EventTimeConfig>,
+}
+
+fn default_near_gas_estimate() -> u64 {
+    NEAR_GAS_ESTIMATE
+}
+
+fn default_ban_duration() -> Duration {
+    Duration::from_secs(3600)
+}
+
+fn default_instance_id() -> String {
+    "borealis-banhammer".to_string()
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            incorrect_nonce_threshold: 0,
+            max_gas_threshold: 0,
+            revert_threshold: 0,
+            excessive_gas_threshold: 0,
+            excessive_evm_gas_threshold: None,
+            near_gas_estimate: default_near_gas_estimate(),
+            token_multiplier: 1,
+            leaky_buckets: vec![],
+            ban_duration: default_ban_duration(),
+            instance_id: default_instance_id(),
+            bans: BanConfig::default(),
+            failure_ratio: None,
+            rules: default_rules(),
+            exemptions: Exemptions::default(),
+            subnets: None,
+            cardinality: None,
+            hot_contracts: HotContractConfig::default(),
+            nonces: None,
+            methods: MethodPolicies::default(),
+            token_tiers: None,
+            reputation: None,
+            bucket_limits: vec![],
+            event_time: None,
+        }
+    }
 }
 
 impl Config {
@@ -52,6 +192,218 @@ impl Config {
         }
         None
     }
+
+    /// Live buckets cap of identity kind
+    pub fn get_bucket_limit(&self, kind: &BucketIdentity) -> Option<usize> {
+        self.bucket_limits
+            .iter()
+            .find(|limit| &limit.identity == kind)
+            .map(|limit| limit.max_buckets)
+    }
+
+    /// Bucket config for RPC method: method policy bucket config,
+    /// common bucket config by default
+    pub fn get_method_bucket_config(
+        &self,
+        kind: &BucketIdentity,
+        err: &BucketErrorKind,
+        method: &str,
+    ) -> Option<BucketConfig> {
+        self.methods
+            .get(method)
+            .and_then(|policy| policy.get_bucket_config(kind, err))
+            .or_else(|| self.get_bucket_config(kind, err))
+    }
+
+    /// Bucket retention, the longest one of common, method
+    /// policies and token tiers bucket configs
+    pub fn get_retention(&self, kind: &BucketIdentity, err: &BucketErrorKind) -> Option<Duration> {
+        let tiers = self
+            .token_tiers
+            .iter()
+            .flat_map(|config| config.tiers.iter());
+        self.methods
+            .iter()
+            .filter_map(|policy| policy.get_bucket_config(kind, err))
+            .chain(tiers.filter_map(|tier| tier.get_bucket_config(kind, err)))
+            .chain(self.get_bucket_config(kind, err))
+            .map(|config| config.retention)
+            .max()
+    }
+
+    /// Tier of message token in registry.
+    /// Return: None if token tiers aren't set or there is no token
+    pub fn get_token_tier(
+        &self,
+        token: Option<&Token>,
+        registry: &TokenRegistry,
+    ) -> Option<&TokenTier> {
+        self.token_tiers.as_ref()?.tier(token?, registry)
+    }
+
+    /// Bucket config and overflow threshold for relayer message.
+    /// Token tier bucket config is used as is, threshold of method
+    /// policy or common bucket config is multiplied by tier multiplier,
+    /// `token_multiplier` is used for token without tier.
+    /// Return: None if bucket config or threshold isn't set
+    pub fn get_message_bucket(
+        &self,
+        kind: &BucketIdentity,
+        err: &BucketErrorKind,
+        method: &str,
+        token_exist: bool,
+        tier: Option<&TokenTier>,
+    ) -> Option<(BucketConfig, u64)> {
+        if let Some(bucket) = tier.and_then(|tier| tier.get_bucket_config(kind, err)) {
+            return Some((bucket, self.threshold(Some(bucket), err, 1)?));
+        }
+        let bucket = self.get_method_bucket_config(kind, err, method)?;
+        let multiplier = match tier {
+            Some(tier) => tier.multiplier,
+            None if token_exist => self.token_multiplier,
+            None => 1,
+        };
+        Some((bucket, self.threshold(Some(bucket), err, multiplier)?))
+    }
+
+    /// Identities of relayer message in processing order:
+    /// client IP, address, API token, IP subnet, target
+    /// contract and composite identities
+    pub fn identities(&self, input: &RelayerMessage) -> Vec<(BucketIdentity, BucketNameValue)> {
+        let mut identities = vec![
+            (BucketIdentity::IP, BucketNameValue::IP(input.client)),
+            (
+                BucketIdentity::Address,
+                BucketNameValue::Address(input.params.from),
+            ),
+        ];
+        if let Some(token) = input.token.clone() {
+            identities.push((BucketIdentity::Token, BucketNameValue::Token(token)));
+        }
+        if let Some(subnets) = self.subnets {
+            identities.push((
+                BucketIdentity::Subnet,
+                BucketNameValue::Subnet(subnets.subnet(input.client)),
+            ));
+        }
+        if let Some(to) = input.params.to {
+            identities.push((BucketIdentity::Contract, BucketNameValue::Contract(to)));
+        }
+        for identity in self.composite_identities() {
+            if let Some(value) = self.identity_value(&identity, input) {
+                identities.push((identity, value));
+            }
+        }
+        identities
+    }
+
+    /// Composite identities configured in leaky buckets
+    pub fn composite_identities(&self) -> Vec<BucketIdentity> {
+        let mut identities = vec![];
+        for config in self.leaky_buckets.iter() {
+            if let BucketIdentity::Composite(_) = config.identity {
+                if !identities.contains(&config.identity) {
+                    identities.push(config.identity.clone());
+                }
+            }
+        }
+        identities
+    }
+
+    /// Identity value of relayer message.
+    /// Return: None if message has no value for identity
+    pub fn identity_value(
+        &self,
+        identity: &BucketIdentity,
+        input: &RelayerMessage,
+    ) -> Option<BucketNameValue> {
+        match identity {
+            BucketIdentity::IP => Some(BucketNameValue::IP(input.client)),
+            BucketIdentity::Address => Some(BucketNameValue::Address(input.params.from)),
+            BucketIdentity::Token => input.token.clone().map(BucketNameValue::Token),
+            BucketIdentity::Contract => input.params.to.map(BucketNameValue::Contract),
+            BucketIdentity::Subnet => Some(BucketNameValue::Subnet(
+                self.subnets.unwrap_or_default().subnet(input.client),
+            )),
+            BucketIdentity::Composite(parts) => parts
+                .iter()
+                .map(|part| self.identity_value(part, input))
+                .collect::<Option<Vec<_>>>()
+                .map(BucketNameValue::Composite),
+        }
+    }
+
+    /// Overflow threshold for bucket: `overflow_size` of the bucket
+    /// config, global threshold for error kind by default.
+    /// Gas thresholds are set in Tgas (NEAR gas) and Mgas (EVM gas).
+    /// Return: None if threshold isn't set for bucket
+    fn threshold(
+        &self,
+        bucket: Option<BucketConfig>,
+        err: &BucketErrorKind,
+        multiplier: u64,
+    ) -> Option<u64> {
+        let (default_threshold, unit) = match err {
+            BucketErrorKind::IncorrectNonce
+            | BucketErrorKind::NonceTooLow
+            | BucketErrorKind::NonceTooHigh => (Some(self.incorrect_nonce_threshold), 1),
+            BucketErrorKind::MaxGas => (Some(self.max_gas_threshold), 1),
+            BucketErrorKind::Reverts => (Some(self.revert_threshold), 1),
+            BucketErrorKind::UsedExcessiveGas => {
+                (Some(self.excessive_gas_threshold), 1_000_000_000_000)
+            }
+            BucketErrorKind::UsedExcessiveEvmGas => (self.excessive_evm_gas_threshold, 1_000_000),
+            BucketErrorKind::FailureRatio
+            | BucketErrorKind::DistinctAddresses
+            | BucketErrorKind::Custom(_) => (None, 1),
+        };
+        let threshold = bucket
+            .and_then(|config| config.overflow_size)
+            .or(default_threshold)?;
+        // Big tier multipliers saturate instead of overflow
+        Some(threshold.saturating_mul(unit).saturating_mul(multiplier))
+    }
+}
+
+/// Relayer message data for buckets processing
+#[derive(Debug, Clone, Copy)]
+struct InputData<'a> {
+    rule_input: RuleInput<'a>,
+    client: IpAddr,
+    from: Address,
+    token: Option<&'a Token>,
+    timestamp: u64,
+    /// Fill weight of RPC method
+    weight: u64,
+}
+
+impl<'a> InputData<'a> {
+    fn new(input: &'a RelayerMessage, config: &Config, nonce_gap: Option<NonceGap>) -> Self {
+        let near_gas = match input.params.near_gas {
+            0 => config.near_gas_estimate,
+            near_gas => u64::try_from(near_gas).unwrap_or(u64::MAX),
+        };
+        Self {
+            rule_input: RuleInput {
+                maybe_error: input.error.as_ref(),
+                method: &input.method,
+                status: input.status.as_u16(),
+                to: input.params.to,
+                near_gas,
+                eth_gas: input.params.eth_gas as u64,
+                nonce_gap,
+                reputation: None,
+            },
+            client: input.client,
+            from: input.params.from,
+            token: input.token.as_ref(),
+            timestamp: input.timestamp.as_millis(),
+            weight: config
+                .methods
+                .get(&input.method)
+                .map_or(1, |policy| policy.weight),
+        }
+    }
 }
 
 #[derive(Debug, Hash, Clone, Eq, PartialEq)]
@@ -60,55 +412,438 @@ pub struct RetentionKey {
     pub error: BucketErrorKind,
 }
 
+impl RetentionKey {
+    fn new(bucket: &BucketName) -> Self {
+        Self {
+            kind: bucket.identity(),
+            error: bucket.error(),
+        }
+    }
+}
+
+/// Buckets last update queues by retention key
 pub struct Priorities {
-    pub ip_revert: BucketPriorityQueue,
+    queues: HashMap<RetentionKey, BucketPriorityQueue>,
+    clock: SharedClock,
 }
 
 impl Priorities {
-    pub fn insert(&mut self, bucket: &BucketName) {
-        match bucket.identity() {
-            BucketIdentity::IP => match bucket.error() {
-                BucketErrorKind::UsedExcessiveGas => (),
-                BucketErrorKind::MaxGas => (),
-                BucketErrorKind::Reverts => (),
-                BucketErrorKind::IncorrectNonce => (),
-                BucketErrorKind::Custom(_) => (),
-            },
-            BucketIdentity::Address => match bucket.error() {
-                BucketErrorKind::UsedExcessiveGas => (),
-                BucketErrorKind::MaxGas => (),
-                BucketErrorKind::Reverts => (),
-                BucketErrorKind::IncorrectNonce => (),
-                BucketErrorKind::Custom(_) => (),
-            },
-            BucketIdentity::Token => match bucket.error() {
-                BucketErrorKind::UsedExcessiveGas => (),
-                BucketErrorKind::MaxGas => (),
-                BucketErrorKind::Reverts => (),
-                BucketErrorKind::IncorrectNonce => (),
-                BucketErrorKind::Custom(_) => (),
-            },
+    pub fn new(clock: SharedClock) -> Self {
+        Self {
+            queues: HashMap::new(),
+            clock,
+        }
+    }
+
+    /// Set bucket last update as current time and its fill
+    pub fn insert(&mut self, bucket: &BucketName, fill: BucketValue) {
+        let clock = &self.clock;
+        self.queues
+            .entry(RetentionKey::new(bucket))
+            .or_insert_with(|| BucketPriorityQueue::with_clock(clock.clone()))
+            .push_fill(bucket.clone(), fill);
+    }
+
+    /// Queued buckets count of identity kind
+    pub fn len(&self, kind: &BucketIdentity) -> usize {
+        self.queues
+            .iter()
+            .filter(|(key, _)| &key.kind == kind)
+            .map(|(_, queue)| queue.len())
+            .sum()
+    }
+
+    /// Pop least recently updated buckets with the lowest fill
+    /// of identity kind and drop empty queues, `keep` bucket isn't
+    /// evicted even if it has the same priority
+    pub fn evict(
+        &mut self,
+        kind: &BucketIdentity,
+        count: usize,
+        keep: &BucketName,
+    ) -> Vec<BucketName> {
+        let mut buckets = vec![];
+        let mut kept = None;
+        while buckets.len() < count {
+            // Queue with the greatest head priority of identity kind
+            let key = self
+                .queues
+                .iter()
+                .filter(|(key, _)| &key.kind == kind)
+                .filter_map(|(key, queue)| queue.peek().map(|(_, priority)| (*priority, key)))
+                .max_by_key(|(priority, _)| *priority)
+                .map(|(_, key)| key.clone());
+            let key = match key {
+                Some(key) => key,
+                None => break,
+            };
+            if let Some(queue) = self.queues.get_mut(&key) {
+                match queue.pop() {
+                    Some((bucket, Reverse((_, fill)))) if &bucket == keep => {
+                        kept = Some((bucket, fill));
+                    }
+                    Some((bucket, _)) => buckets.push(bucket),
+                    None => (),
+                }
+                if queue.is_empty() {
+                    self.queues.remove(&key);
+                }
+            }
+        }
+        // Kept bucket is queued back with its fill and the current time
+        if let Some((bucket, fill)) = kept {
+            self.insert(&bucket, fill);
+        }
+        buckets
+    }
+
+    /// Retention keys with queued buckets
+    pub fn keys(&self) -> Vec<RetentionKey> {
+        self.queues.keys().cloned().collect()
+    }
+
+    /// Pop buckets not updated for retention time (in sec)
+    /// and drop empty queue
+    pub fn retention_free(&mut self, key: &RetentionKey, retention_time: u64) -> Vec<BucketName> {
+        let queue = match self.queues.get_mut(key) {
+            Some(queue) => queue,
+            None => return vec![],
+        };
+        let buckets = queue.retention_free(retention_time);
+        if queue.peek().is_none() {
+            self.queues.remove(key);
         }
+        buckets
     }
 }
+
 /// Basic Banhammer data struct
 pub struct Banhammer {
     next_retention_check: HashMap<RetentionKey, Duration>,
     config: Config,
     leaky_buckets: LeakyBucket,
     bucket_pq: Priorities,
+    bans: BanRegistry,
+    failure_ratio: FailureRatio,
+    cardinality: Cardinality,
+    hot_contracts: HotContracts,
+    hot_contract_events: Vec<HotContractEvent>,
+    nonces: NonceTracker,
+    tokens: TokenRegistry,
+    reputation: Reputation,
+    /// Buckets evicted over live buckets caps since the last tick
+    capped_buckets: HashMap<BucketIdentity, u64>,
+    clock: SharedClock,
+    metrics: SharedMetrics,
 }
 
 impl Banhammer {
     pub fn new(config: Config) -> Self {
+        Self::with_clock(config, SystemClock::shared())
+    }
+
+    /// Banhammer with custom time source,
+    /// it's wall clock for event time
+    pub fn with_clock(config: Config, clock: SharedClock) -> Self {
+        let clock = match config.event_time {
+            Some(event_time) => EventClock::shared(clock, event_time),
+            None => clock,
+        };
         Self {
             next_retention_check: HashMap::new(),
             config,
-            leaky_buckets: LeakyBucket::default(),
-            bucket_pq: Priorities {
-                ip_revert: BucketPriorityQueue::default(),
-            },
+            leaky_buckets: LeakyBucket::with_clock(clock.clone()),
+            bucket_pq: Priorities::new(clock.clone()),
+            bans: BanRegistry::default(),
+            failure_ratio: FailureRatio::default(),
+            cardinality: Cardinality::default(),
+            hot_contracts: HotContracts::default(),
+            hot_contract_events: vec![],
+            nonces: NonceTracker::default(),
+            tokens: TokenRegistry::default(),
+            reputation: Reputation::default(),
+            capped_buckets: HashMap::new(),
+            clock,
+            metrics: LiveMetrics::shared(),
+        }
+    }
+
+    /// Replace metrics sink, live series by default.
+    /// Candidate config in shadow evaluation writes to its own series
+    pub fn set_metrics(&mut self, metrics: SharedMetrics) {
+        self.metrics = metrics;
+    }
+
+    /// Active bans registry
+    pub fn bans(&self) -> &BanRegistry {
+        &self.bans
+    }
+
+    /// Lift active ban for identity value manually
+    pub fn unban(&mut self, value: &BucketNameValue) -> Option<UnbanEvent> {
+        let now = self.clock.now_secs();
+        self.bans.lift(value, now).map(|mut unban_event| {
+            unban_event.instance_id = self.config.instance_id.clone();
+            unban_event
+        })
+    }
+
+    /// Replace current config, buckets state is kept
+    pub fn update_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    /// Current reputation score of identity value, None
+    /// if reputation is disabled or value isn't scored
+    pub fn reputation(&self, value: &BucketNameValue) -> Option<f64> {
+        let config = self.config.reputation.as_ref()?;
+        self.reputation.get(value, self.clock.now(), config)
+    }
+
+    /// Record transaction in reputation of client IP, address or token.
+    /// Return: updated score
+    fn score(
+        &mut self,
+        bucket_identity: &BucketIdentity,
+        bucket_value: &BucketNameValue,
+        rule_input: &RuleInput,
+    ) -> Option<f64> {
+        match bucket_identity {
+            BucketIdentity::IP | BucketIdentity::Address | BucketIdentity::Token => (),
+            _ => return None,
+        }
+        let config = self.config.reputation.as_ref()?;
+        let points = config.weights.points(
+            rule_input.maybe_error,
+            rule_input.near_gas,
+            self.config.near_gas_estimate,
+        );
+        let score = self
+            .reputation
+            .record(bucket_value, points, self.clock.now(), config);
+        self.metrics
+            .observe(Histogram::ReputationScore(bucket_identity.clone()), score);
+        Some(score)
+    }
+
+    /// Replace token registry used for token tiers
+    pub fn update_token_registry(&mut self, tokens: TokenRegistry) {
+        self.tokens = tokens;
+    }
+
+    /// Register ban for bucket identity value and build ban event
+    fn ban(
+        &mut self,
+        bucket_name: BucketName,
+        fill: u64,
+        threshold: u64,
+        bucket: Option<BucketConfig>,
+        first_offence: Option<u64>,
+        last_offence: Option<u64>,
+    ) -> Option<BanEvent> {
+        let banned_at = self.clock.now_secs();
+        // Value is already banned for the same reason
+        if self
+            .bans
+            .is_banned_for(&bucket_name.value(), &bucket_name.error(), banned_at)
+        {
+            tracing::debug!("duplicate ban suppressed: {bucket_name:?}");
+            self.metrics.inc(Counter::SuppressedBans(
+                bucket_name.identity(),
+                bucket_name.error(),
+            ));
+            return None;
+        }
+        let duration = self.bans.ban(
+            &bucket_name.identity(),
+            &bucket_name.value(),
+            &bucket_name.error(),
+            banned_at,
+            self.config.ban_duration,
+            &self.config.bans,
+        );
+        Some(BanEvent {
+            identity: bucket_name.identity(),
+            value: bucket_name.value(),
+            error: bucket_name.error(),
+            fill,
+            threshold,
+            bucket,
+            first_offence,
+            last_offence,
+            banned_at,
+            duration,
+            level: Level::Ban,
+            requests_per_second: None,
+            instance_id: self.config.instance_id.clone(),
+            incident_id: String::new(),
+        })
+    }
+
+    /// Set bucket level below overflow and build event
+    /// if bucket reached higher level.
+    /// Return: warn or throttle event
+    fn raise_level(
+        &mut self,
+        bucket_name: &BucketName,
+        levels: &LevelConfig,
+        fill_result: u64,
+        threshold: u64,
+        config: BucketConfig,
+        fill: u64,
+    ) -> Option<BanEvent> {
+        // Contract isn't banned, it has no levels
+        if let BucketIdentity::Contract = bucket_name.identity() {
+            return None;
+        }
+        let level = levels.level(fill_result, threshold);
+        let previous = self.leaky_buckets.set_level(bucket_name, level);
+        if level <= previous {
+            return None;
+        }
+        let now = self.clock.now_secs();
+        // Value is already banned for the same reason
+        if self
+            .bans
+            .is_banned_for(&bucket_name.value(), &bucket_name.error(), now)
+        {
+            return None;
+        }
+        let level = level?;
+        let (first_offence, last_offence) = self
+            .leaky_buckets
+            .get(bucket_name)
+            .map(|data| (data.first_offence, data.last_offence))
+            .unwrap_or_default();
+        let requests_per_second = match level {
+            Level::Throttle => Some(requests_per_second(&config, fill)),
+            _ => None,
+        };
+        Some(BanEvent {
+            identity: bucket_name.identity(),
+            value: bucket_name.value(),
+            error: bucket_name.error(),
+            fill: fill_result,
+            threshold,
+            bucket: Some(config),
+            first_offence,
+            last_offence,
+            banned_at: now,
+            duration: 0,
+            level,
+            requests_per_second,
+            instance_id: self.config.instance_id.clone(),
+            incident_id: String::new(),
+        })
+    }
+
+    /// Mark contract hot and queue hot contract event
+    fn heat_contract(
+        &mut self,
+        bucket_name: BucketName,
+        fill: u64,
+        threshold: u64,
+        bucket: Option<BucketConfig>,
+        first_offence: Option<u64>,
+        last_offence: Option<u64>,
+    ) {
+        let contract = match bucket_name.value() {
+            BucketNameValue::Contract(contract) => contract,
+            _ => return,
+        };
+        let detected_at = self.clock.now_secs();
+        let hot_until = self
+            .hot_contracts
+            .heat(contract, detected_at, &self.config.hot_contracts);
+        self.hot_contract_events.push(HotContractEvent {
+            contract,
+            error: bucket_name.error(),
+            fill,
+            threshold,
+            bucket,
+            first_offence,
+            last_offence,
+            detected_at,
+            hot_until,
+            instance_id: self.config.instance_id.clone(),
+        });
+    }
+
+    /// Hot contract events produced since the last call
+    pub fn take_hot_contract_events(&mut self) -> Vec<HotContractEvent> {
+        std::mem::take(&mut self.hot_contract_events)
+    }
+
+    /// Count transaction result and check failed
+    /// transactions share for identity value.
+    /// Return: ban event
+    fn check_failure_ratio(
+        &mut self,
+        bucket_identity: &BucketIdentity,
+        bucket_value: &BucketNameValue,
+        maybe_error: Option<&TransactionError>,
+        timestamp: u64,
+    ) -> Option<BanEvent> {
+        // Contract isn't banned
+        if let BucketIdentity::Contract = bucket_identity {
+            return None;
+        }
+        let config = self.config.failure_ratio.clone()?;
+        let failed = match maybe_error {
+            None => false,
+            // Relayer issues aren't user's failures
+            Some(TransactionError::Relayer(_)) => return None,
+            Some(_) => true,
+        };
+        let now = self.clock.now_secs();
+        let counters = self
+            .failure_ratio
+            .count(bucket_value, failed, now, timestamp, &config)?;
+        self.ban(
+            BucketName::new(
+                bucket_identity.clone(),
+                bucket_value.clone(),
+                BucketErrorKind::FailureRatio,
+            ),
+            counters.failure_percent(),
+            config.max_failure_percent,
+            None,
+            counters.first_failure,
+            counters.last_failure,
+        )
+    }
+
+    /// Add sender address and check distinct addresses
+    /// count for client IP or token.
+    /// Return: ban event
+    fn check_cardinality(
+        &mut self,
+        bucket_identity: &BucketIdentity,
+        bucket_value: &BucketNameValue,
+        from: Address,
+        timestamp: u64,
+    ) -> Option<BanEvent> {
+        match bucket_identity {
+            BucketIdentity::IP | BucketIdentity::Token => (),
+            _ => return None,
         }
+        let config = self.config.cardinality.clone()?;
+        let now = self.clock.now_secs();
+        let addresses = self
+            .cardinality
+            .count(bucket_value, from, now, timestamp, &config)?;
+        self.ban(
+            BucketName::new(
+                bucket_identity.clone(),
+                bucket_value.clone(),
+                BucketErrorKind::DistinctAddresses,
+            ),
+            addresses.len(),
+            config.max_addresses,
+            None,
+            addresses.first_seen,
+            addresses.last_seen,
+        )
     }
 
     /// Check bucket by threshold and process
@@ -116,221 +851,372 @@ impl Banhammer {
     /// Return: ban event
     fn check_and_change_bucket(
         &mut self,
-        bucket_identity: &BucketIdentity,
-        bucket_value: &BucketNameValue,
-        bucket_error_kind: BucketErrorKind,
+        bucket_name: BucketName,
+        config: BucketConfig,
         threshold: u64,
         fill: u64,
-    ) -> Option<BucketName> {
+        timestamp: u64,
+    ) -> Option<BanEvent> {
         let mut ban_event = None;
-        let bucket_name = BucketName::new(
-            bucket_identity.clone(),
-            bucket_value.clone(),
-            bucket_error_kind.clone(),
-        );
-        let fill_result = self.leaky_buckets.get_fill(&bucket_name, fill);
-        let config = self
-            .config
-            .get_bucket_config(bucket_identity, &bucket_error_kind)
-            .unwrap();
+        let fill_result = self.leaky_buckets.check(&bucket_name, fill, &config);
         // Check overflow
         if fill_result >= threshold {
-            ban_event = Some(bucket_name.clone());
+            let (mut first_offence, mut last_offence) = self
+                .leaky_buckets
+                .get(&bucket_name)
+                .map(|data| (data.first_offence, data.last_offence))
+                .unwrap_or_default();
+            if fill > 0 {
+                first_offence.get_or_insert(timestamp);
+                last_offence = Some(timestamp);
+            }
+            // Contract isn't banned, it becomes hot
+            if let BucketIdentity::Contract = bucket_name.identity() {
+                self.heat_contract(
+                    bucket_name.clone(),
+                    fill_result,
+                    threshold,
+                    Some(config),
+                    first_offence,
+                    last_offence,
+                );
+            } else {
+                ban_event = self.ban(
+                    bucket_name.clone(),
+                    fill_result,
+                    threshold,
+                    Some(config),
+                    first_offence,
+                    last_offence,
+                );
+            }
             // Set leaky bucket ti base size after overflow
-            self.leaky_buckets.fill(&bucket_name, config.base_size)
+            self.leaky_buckets.reset(&bucket_name, &config)
         } else {
-            // Check leaky status and leak if it needed
-            self.leaky_buckets.leaky(&bucket_name, &config);
-
-            let r = self.leaky_buckets.get_fill(&bucket_name, fill);
-            println!("{}", r);
-
-            // Fill bucket
-            self.leaky_buckets.fill(&bucket_name, fill_result)
+            // Leak and fill bucket
+            self.leaky_buckets.add(&bucket_name, fill, &config);
+            if fill > 0 {
+                self.leaky_buckets.offend(&bucket_name, timestamp);
+            }
+            if let Some(levels) = config.levels {
+                ban_event =
+                    self.raise_level(&bucket_name, &levels, fill_result, threshold, config, fill);
+            }
         }
         // Set priority queue for bucket with
         // last_update field as current time
-        self.bucket_pq.insert(&bucket_name);
+        let fill_value = self
+            .leaky_buckets
+            .get(&bucket_name)
+            .map(|data| data.value)
+            .unwrap_or_default();
+        self.bucket_pq.insert(&bucket_name, fill_value);
+        self.limit_buckets(&bucket_name);
         ban_event
     }
 
-    /// Process bucket live cycle    
+    /// Evict least recently updated buckets with the lowest fill
+    /// over live buckets cap of identity kind. Bucket of the
+    /// current message is never evicted
+    fn limit_buckets(&mut self, bucket_name: &BucketName) {
+        let kind = &bucket_name.identity();
+        let max_buckets = match self.config.get_bucket_limit(kind) {
+            Some(max_buckets) => max_buckets,
+            None => return,
+        };
+        let live_buckets = self.bucket_pq.len(kind);
+        if live_buckets <= max_buckets {
+            return;
+        }
+        let buckets = self
+            .bucket_pq
+            .evict(kind, live_buckets - max_buckets, bucket_name);
+        for bucket in buckets.iter() {
+            tracing::debug!("bucket evicted over cap: {bucket:?}");
+            self.leaky_buckets.remove(bucket);
+        }
+        self.metrics
+            .inc_by(Counter::CappedBuckets(kind.clone()), buckets.len() as u64);
+        *self.capped_buckets.entry(kind.clone()).or_default() += buckets.len() as u64;
+    }
+
+    /// Process bucket live cycle
     fn process_bucket(
         &mut self,
         bucket_identity: BucketIdentity,
         bucket_value: BucketNameValue,
-        maybe_error: Option<&TransactionError>,
-        near_gas: u64,
-        token_exist: bool,
-    ) -> Vec<BucketName> {
+        data: &InputData,
+    ) -> Vec<BanEvent> {
+        let InputData {
+            rule_input,
+            client,
+            from,
+            token,
+            timestamp,
+            weight,
+        } = *data;
         let mut ban_events = vec![];
-        let near_gas_threshold = {
-            if token_exist {
-                self.config.excessive_gas_threshold
-                    * 1_000_000_000_000
-                    * self.config.token_multiplier
-            } else {
-                self.config.excessive_gas_threshold * 1_000_000_000_000
+
+        let multiplier = match self.config.exemptions.get_message(&bucket_value, &client) {
+            Some(Exempt::Full) => return ban_events,
+            Some(Exempt::Multiplier(multiplier)) => multiplier,
+            None => 1,
+        };
+        // Senders calling hot contract get stricter thresholds
+        let divisor = match (&bucket_identity, rule_input.to) {
+            (BucketIdentity::Contract, _) => 1,
+            (_, Some(to)) if self.hot_contracts.is_hot(&to, self.clock.now_secs()) => {
+                self.config.hot_contracts.threshold_divisor.max(1)
             }
+            _ => 1,
+        };
+        // Reputation score including the transaction is rules input
+        let rule_input = RuleInput {
+            reputation: self.score(&bucket_identity, &bucket_value, &rule_input),
+            ..rule_input
         };
+        let tier = self.config.get_token_tier(token, &self.tokens);
+        let fills: Vec<(BucketErrorKind, BucketConfig, u64, u64)> = self
+            .config
+            .rules
+            .iter()
+            .filter_map(|rule| {
+                let fill = rule.apply(&bucket_identity, &rule_input)?;
+                let (bucket, threshold) = self.config.get_message_bucket(
+                    &bucket_identity,
+                    &rule.error_kind,
+                    rule_input.method,
+                    token.is_some(),
+                    tier,
+                )?;
+                Some((rule.error_kind.clone(), bucket, threshold, fill))
+            })
+            .collect();
+
+        for (bucket_error_kind, bucket, threshold, fill) in fills {
+            let bucket_name = BucketName::new(
+                bucket_identity.clone(),
+                bucket_value.clone(),
+                bucket_error_kind,
+            );
+            if let Some(ban_event) = self.check_and_change_bucket(
+                bucket_name,
+                bucket,
+                // Stricter threshold doesn't ban at once
+                (threshold.saturating_mul(multiplier) / divisor).max(1),
+                fill.saturating_mul(weight),
+                timestamp,
+            ) {
+                ban_events.push(ban_event);
+            }
+        }
 
-        if let Some(ban_event) = self.check_and_change_bucket(
+        if let Some(ban_event) = self.check_failure_ratio(
             &bucket_identity,
             &bucket_value,
-            BucketErrorKind::UsedExcessiveGas,
-            near_gas_threshold,
-            near_gas,
+            rule_input.maybe_error,
+            timestamp,
         ) {
             ban_events.push(ban_event);
         }
 
-        // if it's no errors - just return
-        if maybe_error.is_none() {
-            return ban_events;
+        if let Some(ban_event) =
+            self.check_cardinality(&bucket_identity, &bucket_value, from, timestamp)
+        {
+            ban_events.push(ban_event);
         }
 
-        match maybe_error.unwrap() {
-            TransactionError::ErrIncorrectNonce | TransactionError::InvalidECDSA => {
-                let threshold = {
-                    if token_exist {
-                        self.config.incorrect_nonce_threshold * self.config.token_multiplier
-                    } else {
-                        self.config.incorrect_nonce_threshold
-                    }
-                } as u64;
+        ban_events
+    }
 
-                if let Some(ban_event) = self.check_and_change_bucket(
-                    &bucket_identity,
-                    &bucket_value,
-                    BucketErrorKind::IncorrectNonce,
-                    threshold,
-                    1,
-                ) {
-                    ban_events.push(ban_event);
-                }
-            }
-            TransactionError::MaxGas => {
-                let threshold = {
-                    if token_exist {
-                        self.config.max_gas_threshold * self.config.token_multiplier
-                    } else {
-                        self.config.max_gas_threshold
-                    }
-                } as u64;
+    /// Live leaky buckets count
+    pub fn live_buckets(&self) -> usize {
+        self.leaky_buckets.len()
+    }
 
-                if let Some(ban_event) = self.check_and_change_bucket(
-                    &bucket_identity,
-                    &bucket_value,
-                    BucketErrorKind::MaxGas,
-                    threshold,
-                    1,
-                ) {
-                    ban_events.push(ban_event);
-                }
+    /// Tick for retention time for leaky bucket and bans expiry,
+    /// set live buckets gauge.
+    /// Return: unban events for expired bans
+    pub fn tick(&mut self) -> Vec<UnbanEvent> {
+        let unban_events = self.sweep();
+        self.metrics
+            .set(Gauge::LiveBuckets, self.leaky_buckets.len() as i64);
+        unban_events
+    }
+
+    /// Tick without live buckets gauge, used by shards
+    /// of sharded Banhammer which sets the total.
+    /// Return: unban events for expired bans
+    pub fn sweep(&mut self) -> Vec<UnbanEvent> {
+        let current_time = self.clock.now();
+        let mut evicted = 0;
+        for key in self.bucket_pq.keys() {
+            // Buckets without config are removed at once
+            let retention = self
+                .config
+                .get_retention(&key.kind, &key.error)
+                .unwrap_or_default();
+            let next_retention = self
+                .next_retention_check
+                .entry(key.clone())
+                .or_insert(current_time + retention);
+            if current_time < *next_retention {
+                continue;
             }
-            TransactionError::Revert(_) => {
-                let threshold = {
-                    if token_exist {
-                        self.config.revert_threshold * self.config.token_multiplier
-                    } else {
-                        self.config.revert_threshold
-                    }
-                } as u64;
+            *next_retention = current_time + retention;
 
-                if let Some(ban_event) = self.check_and_change_bucket(
-                    &bucket_identity,
-                    &bucket_value,
-                    BucketErrorKind::Reverts,
-                    threshold,
-                    1,
-                ) {
-                    ban_events.push(ban_event);
-                }
+            for bucket in self.bucket_pq.retention_free(&key, retention.as_secs()) {
+                tracing::debug!("bucket removed: {bucket:?}");
+                self.leaky_buckets.remove(&bucket);
+                evicted += 1;
             }
-            TransactionError::Relayer(_) => (),
         }
-        ban_events
+        let bucket_pq = &self.bucket_pq;
+        self.next_retention_check
+            .retain(|key, _| bucket_pq.queues.contains_key(key));
+        self.metrics.inc_by(Counter::EvictedBuckets, evicted);
+        for (kind, capped) in self.capped_buckets.drain() {
+            tracing::warn!(
+                "live buckets cap of {kind:?} is hit, {capped} buckets evicted since last tick"
+            );
+        }
+
+        let now = current_time.as_secs();
+        if let Some(config) = self.config.failure_ratio.as_ref() {
+            self.failure_ratio.retention_free(now, config);
+        }
+        if let Some(config) = self.config.cardinality.as_ref() {
+            self.cardinality.retention_free(now, config);
+        }
+        self.hot_contracts.expire(now);
+        if let Some(config) = self.config.nonces.as_ref() {
+            self.nonces.retention_free(now, config);
+        }
+        if let Some(config) = self.config.reputation.as_ref() {
+            self.reputation.retention_free(current_time, config);
+        }
+        let mut unban_events = self.bans.expire(now, &self.config.bans);
+        self.limit_values();
+        for unban_event in unban_events.iter_mut() {
+            unban_event.instance_id = self.config.instance_id.clone();
+        }
+        unban_events
     }
 
-    /// Tick for retention time for leaky bucket
-    pub fn tick(&mut self, time: Instant) {
-        for (key, next_retention) in self.next_retention_check.iter_mut() {
-            if &time.elapsed() > next_retention {
-                // Get buckets fpr remove.
-                // Retention time in seconds
-                let _config = self
-                    .config
-                    .get_bucket_config(&key.kind, &key.error)
-                    .unwrap();
-
-                let buckets_to_remove = self.bucket_pq.ip_revert.retention_free(60);
-                for bucket in buckets_to_remove {
-                    tracing::info!("bucket removed: {bucket:?}");
-                    self.leaky_buckets.remove(&bucket);
-                }
-                let config = self
-                    .config
-                    .get_bucket_config(&key.kind, &key.error)
-                    .unwrap();
-                *next_retention += config.retention;
+    /// Evict least recently updated identity values over live buckets
+    /// caps from reputation, failure ratio, distinct addresses, nonces
+    /// and bans history
+    fn limit_values(&mut self) {
+        for limit in self.config.bucket_limits.iter() {
+            let (kind, max) = (&limit.identity, limit.max_buckets);
+            let mut evicted = self.reputation.limit(kind, max)
+                + self.failure_ratio.limit(kind, max)
+                + self.cardinality.limit(kind, max)
+                + self.bans.limit(kind, max);
+            if let BucketIdentity::Address = kind {
+                evicted += self.nonces.limit(max);
+            }
+            if evicted > 0 {
+                tracing::warn!("identity values cap of {kind:?} is hit, {evicted} values evicted");
             }
         }
     }
 
+    /// Register successful nonce of sender address,
+    /// or classify incorrect nonce.
+    /// Return: incorrect nonce class
+    fn track_nonce(&mut self, input: &RelayerMessage) -> Option<NonceGap> {
+        let now = self.clock.now_secs();
+        self.nonces.track(input, self.config.nonces.as_ref(), now)
+    }
+
     /// Read relayer input, process leaky bucket and return ban events list
-    pub fn read_input(&mut self, input: &RelayerMessage) -> Vec<BucketName> {
+    pub fn read_input(&mut self, input: &RelayerMessage) -> Vec<BanEvent> {
         let mut ban_events = vec![];
-        let maybe_error = input.error.as_ref();
-
-        // Check is token exist
-        let token_exist = input.token.is_some();
-
-        // Process leaky buckets for Client IPs
-        let mut events = self.process_bucket(
-            BucketIdentity::IP,
-            BucketNameValue::IP(input.client),
-            maybe_error,
-            NEAR_GAS_COUNTER,
-            token_exist,
-        );
-        ban_events.append(&mut events);
-
-        // Process leaky buckets for Client Eth Addresses
-        let mut events = self.process_bucket(
-            BucketIdentity::Address,
-            BucketNameValue::Address(input.params.from),
-            maybe_error,
-            NEAR_GAS_COUNTER,
-            token_exist,
-        );
-        ban_events.append(&mut events);
+        // Message timestamp moves event time, late message is dropped
+        if !self.clock.observe(input.timestamp.as_duration()) {
+            tracing::debug!("late message dropped: {:?}", input.timestamp);
+            self.metrics.inc(Counter::LateMessages);
+            return ban_events;
+        }
+        if !self.config.methods.evaluates(&input.method) {
+            return ban_events;
+        }
+        let nonce_gap = self.track_nonce(input);
 
-        // Process leaky buckets for Client API tokens
-        if let Some(token) = input.token.clone() {
-            let mut events = self.process_bucket(
-                BucketIdentity::Token,
-                BucketNameValue::Token(token),
-                maybe_error,
-                NEAR_GAS_COUNTER,
-                token_exist,
-            );
+        // Process leaky buckets for every identity of the message
+        for (identity, value) in self.config.identities(input) {
+            let mut events = self.read_identity(input, nonce_gap, identity, value);
             ban_events.append(&mut events);
         }
+        correlate(&mut ban_events, input);
 
         ban_events
     }
+
+    /// Process leaky buckets of one identity value of relayer message,
+    /// `nonce_gap` is incorrect nonce class of the message.
+    /// Ban events aren't correlated into incident
+    pub fn read_identity(
+        &mut self,
+        input: &RelayerMessage,
+        nonce_gap: Option<NonceGap>,
+        identity: BucketIdentity,
+        value: BucketNameValue,
+    ) -> Vec<BanEvent> {
+        // Event time is observed by `read_input` or sharded Banhammer
+        let data = InputData::new(input, &self.config, nonce_gap);
+        self.process_bucket(identity, value, &data)
+    }
+
+    /// Mark contract hot until UNIX time in sec, used to share
+    /// hot contracts detected by other Banhammer instance
+    pub fn mark_hot_contract(&mut self, contract: Address, hot_until: u64) {
+        self.hot_contracts.extend(contract, hot_until);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::MockClock;
+    use crate::de::{Timestamp, Token};
+    use crate::events::Event;
+    use crate::exemptions::Exemption;
+    use crate::stats::{live_series, shadow_series, ShadowMetrics};
     use std::net::{IpAddr, Ipv4Addr};
-    use std::thread::sleep;
 
-    #[test]
-    fn test_ip_excessive_gas_overflow() {
-        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
-        let config = Config {
+    fn input(
+        maybe_error: Option<&TransactionError>,
+        near_gas: u64,
+        timestamp: u64,
+    ) -> InputData<'_> {
+        InputData {
+            rule_input: RuleInput {
+                maybe_error,
+                method: "eth_sendrawtransaction",
+                status: 200,
+                to: None,
+                near_gas,
+                eth_gas: 0,
+                nonce_gap: match maybe_error {
+                    Some(TransactionError::ErrIncorrectNonce) => Some(NonceGap::Unknown),
+                    _ => None,
+                },
+                reputation: None,
+            },
+            client: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            from: Address::zero(),
+            token: None,
+            timestamp,
+            weight: 1,
+        }
+    }
+
+    #[test]
+    fn test_ip_excessive_gas_overflow() {
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let config = Config {
             incorrect_nonce_threshold: 10,
             max_gas_threshold: 2,
             revert_threshold: 10,
@@ -342,18 +1228,18 @@ mod tests {
                 bucket: BucketConfig {
                     base_size: 1,
                     leak_rate: 100000,
-                    overflow_size: 10,
+                    overflow_size: None,
                     retention: Duration::from_secs(10),
+                    ..BucketConfig::default()
                 },
             }],
+            ..Config::default()
         };
         let mut bh = Banhammer::new(config.clone());
         let events = bh.process_bucket(
             BucketIdentity::IP,
             BucketNameValue::IP(ip),
-            None,
-            1_000_000_000_000,
-            false,
+            &input(None, 1_000_000_000_000, 10),
         );
         assert!(events.is_empty());
         let bucket_name = BucketName::new(
@@ -367,9 +1253,7 @@ mod tests {
         let events = bh.process_bucket(
             BucketIdentity::IP,
             BucketNameValue::IP(ip),
-            None,
-            1_000_000_000_000,
-            false,
+            &input(None, 1_000_000_000_000, 20),
         );
         assert!(events.is_empty());
         let res = bh.leaky_buckets.get_fill(&bucket_name, 0);
@@ -378,12 +1262,20 @@ mod tests {
         let events = bh.process_bucket(
             BucketIdentity::IP,
             BucketNameValue::IP(ip),
-            None,
-            1_000_000_000_100,
-            false,
+            &input(None, 1_000_000_000_100, 30),
         );
         assert_eq!(events.len(), 1);
-        assert_eq!(events[0], bucket_name);
+        assert_eq!(events[0].bucket_name(), bucket_name);
+        assert_eq!(events[0].fill, 3_000_000_000_100);
+        assert_eq!(events[0].threshold, 3_000_000_000_000);
+        assert_eq!(events[0].first_offence, Some(10));
+        assert_eq!(events[0].last_offence, Some(30));
+        assert_eq!(events[0].duration, config.ban_duration.as_secs());
+        let now = bh.clock.now_secs();
+        assert!(bh.bans().is_banned(&BucketNameValue::IP(ip), now));
+        let unban_event = bh.unban(&BucketNameValue::IP(ip)).unwrap();
+        assert_eq!(unban_event.error, BucketErrorKind::UsedExcessiveGas);
+        assert!(!bh.bans().is_banned(&BucketNameValue::IP(ip), now));
         let res = bh.leaky_buckets.get_fill(&bucket_name, 0);
         assert_eq!(config.leaky_buckets[0].bucket.base_size, res);
     }
@@ -403,18 +1295,19 @@ mod tests {
                 bucket: BucketConfig {
                     base_size: 1,
                     leak_rate: 100000,
-                    overflow_size: 10,
+                    overflow_size: None,
                     retention: Duration::from_secs(10),
+                    ..BucketConfig::default()
                 },
             }],
+            ..Config::default()
         };
-        let mut bh = Banhammer::new(config.clone());
+        let clock = MockClock::default();
+        let mut bh = Banhammer::with_clock(config, clock.shared());
         let events = bh.process_bucket(
             BucketIdentity::IP,
             BucketNameValue::IP(ip),
-            None,
-            1_000_000_000_000,
-            false,
+            &input(None, 1_000_000_000_000, 0),
         );
         assert!(events.is_empty());
         let bucket_name = BucketName::new(
@@ -427,17 +1320,1251 @@ mod tests {
         let res = bh.leaky_buckets.get_fill(&bucket_name, 0);
         assert_eq!(1_000_000_000_000, res);
 
-        sleep(Duration::from_secs(5));
+        clock.advance(Duration::from_secs(5));
 
         let events = bh.process_bucket(
             BucketIdentity::IP,
             BucketNameValue::IP(ip),
-            None,
-            1_000_000_000_000,
-            false,
+            &input(None, 1_000_000_000_000, 0),
         );
         assert!(events.is_empty());
+        // 5 units leaked over 5 secs are kept: fill isn't
+        // overwritten by the pre-leak fill of the bucket
         let res = bh.leaky_buckets.get_fill(&bucket_name, 0);
-        assert_eq!(2_000_000_000_000, res);
+        assert_eq!(1_999_999_999_995, res);
+    }
+
+    #[test]
+    fn test_ban_expiry() {
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let config = Config {
+            excessive_gas_threshold: 3,
+            leaky_buckets: vec![LeakyBucketConfig {
+                identity: BucketIdentity::IP,
+                error_kind: BucketErrorKind::UsedExcessiveGas,
+                bucket: BucketConfig {
+                    base_size: 1,
+                    leak_rate: 100000,
+                    overflow_size: None,
+                    retention: Duration::from_secs(10),
+                    ..BucketConfig::default()
+                },
+            }],
+            ..Config::default()
+        };
+        let clock = MockClock::default();
+        let mut bh = Banhammer::with_clock(config, clock.shared());
+        let events = bh.process_bucket(
+            BucketIdentity::IP,
+            BucketNameValue::IP(ip),
+            &input(None, 3_000_000_000_000, 0),
+        );
+        assert_eq!(events.len(), 1);
+        // Ban expires on tick
+        clock.advance(Duration::from_secs(events[0].duration - 1));
+        assert!(bh.tick().is_empty());
+        clock.advance(Duration::from_secs(1));
+        let unban_events = bh.tick();
+        assert_eq!(unban_events.len(), 1);
+        assert_eq!(unban_events[0].value, BucketNameValue::IP(ip));
+    }
+
+    #[test]
+    fn test_ip_failure_ratio() {
+        let ip = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
+        let bucket = BucketConfig {
+            base_size: 1,
+            leak_rate: 100000,
+            overflow_size: None,
+            retention: Duration::from_secs(10),
+            ..BucketConfig::default()
+        };
+        let config = Config {
+            revert_threshold: 100,
+            excessive_gas_threshold: 3,
+            leaky_buckets: vec![
+                LeakyBucketConfig {
+                    identity: BucketIdentity::IP,
+                    error_kind: BucketErrorKind::UsedExcessiveGas,
+                    bucket,
+                },
+                LeakyBucketConfig {
+                    identity: BucketIdentity::IP,
+                    error_kind: BucketErrorKind::Reverts,
+                    bucket,
+                },
+            ],
+            failure_ratio: Some(FailureRatioConfig {
+                max_failure_percent: 20,
+                min_samples: 5,
+                window: Duration::from_secs(3600),
+            }),
+            ..Config::default()
+        };
+        let revert = TransactionError::Revert("revert".to_string());
+        let mut bh = Banhammer::new(config);
+        for _ in 0..4 {
+            let events = bh.process_bucket(
+                BucketIdentity::IP,
+                BucketNameValue::IP(ip),
+                &input(None, 0, 0),
+            );
+            assert!(events.is_empty());
+        }
+        // 1 of 5 failed
+        let events = bh.process_bucket(
+            BucketIdentity::IP,
+            BucketNameValue::IP(ip),
+            &input(Some(&revert), 0, 10),
+        );
+        assert!(events.is_empty());
+        // 2 of 6 failed
+        let events = bh.process_bucket(
+            BucketIdentity::IP,
+            BucketNameValue::IP(ip),
+            &input(Some(&revert), 0, 20),
+        );
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].error, BucketErrorKind::FailureRatio);
+        assert_eq!(events[0].fill, 33);
+        assert_eq!(events[0].threshold, 20);
+        assert_eq!(events[0].first_offence, Some(10));
+        assert_eq!(events[0].last_offence, Some(20));
+        assert!(events[0].bucket.is_none());
+    }
+
+    fn relayer_message(near_gas: u64) -> RelayerMessage {
+        let mut message = RelayerMessage::test_message();
+        message.params.near_gas = near_gas as u128;
+        message
+    }
+
+    #[test]
+    fn test_read_input_gas() {
+        let bucket = BucketConfig {
+            base_size: 0,
+            leak_rate: 1,
+            overflow_size: None,
+            retention: Duration::from_secs(10),
+            ..BucketConfig::default()
+        };
+        let mut leaky_buckets = vec![];
+        for identity in [BucketIdentity::IP, BucketIdentity::Address] {
+            for error_kind in [
+                BucketErrorKind::UsedExcessiveGas,
+                BucketErrorKind::UsedExcessiveEvmGas,
+            ] {
+                leaky_buckets.push(LeakyBucketConfig {
+                    identity: identity.clone(),
+                    error_kind,
+                    bucket,
+                });
+            }
+        }
+        let config = Config {
+            excessive_gas_threshold: 3,
+            excessive_evm_gas_threshold: Some(10),
+            near_gas_estimate: 1_000_000_000_000,
+            leaky_buckets,
+            ..Config::default()
+        };
+        let mut bh = Banhammer::new(config);
+
+        // NEAR gas estimate is used for 0, EVM gas is 6.7 Mgas
+        assert!(bh.read_input(&relayer_message(0)).is_empty());
+        let events = bh.read_input(&relayer_message(0));
+        assert_eq!(events.len(), 2);
+        for event in events.iter() {
+            assert_eq!(event.error, BucketErrorKind::UsedExcessiveEvmGas);
+            assert_eq!(event.fill, 2 * 6721975);
+            assert_eq!(event.threshold, 10_000_000);
+        }
+
+        // Actual NEAR gas is used
+        let events = bh.read_input(&relayer_message(1_500_000_000_000));
+        assert_eq!(events.len(), 2);
+        for event in events.iter() {
+            assert_eq!(event.error, BucketErrorKind::UsedExcessiveGas);
+            assert_eq!(event.fill, 3_500_000_000_000);
+        }
+    }
+
+    #[test]
+    fn test_bucket_overflow_size() {
+        let bucket = BucketConfig {
+            base_size: 0,
+            leak_rate: 1,
+            overflow_size: None,
+            retention: Duration::from_secs(10),
+            ..BucketConfig::default()
+        };
+        let config = Config {
+            revert_threshold: 3,
+            token_multiplier: 10,
+            leaky_buckets: vec![
+                LeakyBucketConfig {
+                    identity: BucketIdentity::IP,
+                    error_kind: BucketErrorKind::Reverts,
+                    bucket: BucketConfig {
+                        overflow_size: Some(2),
+                        ..bucket
+                    },
+                },
+                LeakyBucketConfig {
+                    identity: BucketIdentity::Address,
+                    error_kind: BucketErrorKind::Reverts,
+                    bucket,
+                },
+                LeakyBucketConfig {
+                    identity: BucketIdentity::Address,
+                    error_kind: BucketErrorKind::UsedExcessiveEvmGas,
+                    bucket,
+                },
+            ],
+            ..Config::default()
+        };
+        let threshold = |kind, err, token_exist| {
+            config
+                .get_message_bucket(&kind, &err, "eth_sendRawTransaction", token_exist, None)
+                .map(|(_, threshold)| threshold)
+        };
+        // Bucket overflow size
+        assert_eq!(
+            threshold(BucketIdentity::IP, BucketErrorKind::Reverts, false),
+            Some(2)
+        );
+        assert_eq!(
+            threshold(BucketIdentity::IP, BucketErrorKind::Reverts, true),
+            Some(20)
+        );
+        // Global threshold by default
+        assert_eq!(
+            threshold(BucketIdentity::Address, BucketErrorKind::Reverts, false),
+            Some(3)
+        );
+        // Global EVM gas threshold isn't set
+        assert_eq!(
+            threshold(
+                BucketIdentity::Address,
+                BucketErrorKind::UsedExcessiveEvmGas,
+                false
+            ),
+            None
+        );
+
+        let ip = BucketNameValue::IP(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        let revert = TransactionError::Revert("revert".to_string());
+        let mut bh = Banhammer::new(config);
+        let events = bh.process_bucket(BucketIdentity::IP, ip.clone(), &input(Some(&revert), 0, 0));
+        assert!(events.is_empty());
+        let events = bh.process_bucket(BucketIdentity::IP, ip, &input(Some(&revert), 0, 0));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].threshold, 2);
+    }
+
+    #[test]
+    fn test_custom_rule() {
+        let config: Config = toml::from_str(
+            r#"
+incorrect_nonce_threshold = 10
+max_gas_threshold = 10
+revert_threshold = 10
+excessive_gas_threshold = 10
+token_multiplier = 1
+
+[[leaky_buckets]]
+identity = "IP"
+error_kind = { Custom = "expired" }
+bucket = { base_size = 0, leak_rate = 3600, overflow_size = 4, retention = 3600 }
+
+[[rules]]
+name = "expired"
+identities = ["IP"]
+weight = 2
+error_kind = { Custom = "expired" }
+predicate = { error = ["Revert"], revert = "EXPIRED$" }
+"#,
+        )
+        .unwrap();
+        let ip = BucketNameValue::IP(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        let expired = TransactionError::Revert("UniswapV2Router: EXPIRED".to_string());
+        let revert = TransactionError::Revert("revert".to_string());
+        let mut bh = Banhammer::new(config);
+        // Built-in rules are replaced
+        for _ in 0..20 {
+            let events =
+                bh.process_bucket(BucketIdentity::IP, ip.clone(), &input(Some(&revert), 0, 0));
+            assert!(events.is_empty());
+        }
+        let events =
+            bh.process_bucket(BucketIdentity::IP, ip.clone(), &input(Some(&expired), 0, 0));
+        assert!(events.is_empty());
+        let events = bh.process_bucket(BucketIdentity::IP, ip, &input(Some(&expired), 0, 0));
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].error,
+            BucketErrorKind::Custom("expired".to_string())
+        );
+        assert_eq!(events[0].fill, 4);
+    }
+
+    #[test]
+    fn test_bucket_retention() {
+        let bucket = BucketConfig {
+            base_size: 0,
+            leak_rate: 1,
+            overflow_size: None,
+            retention: Duration::from_secs(10),
+            ..BucketConfig::default()
+        };
+        let config = Config {
+            revert_threshold: 100,
+            excessive_gas_threshold: 100,
+            leaky_buckets: vec![
+                LeakyBucketConfig {
+                    identity: BucketIdentity::IP,
+                    error_kind: BucketErrorKind::Reverts,
+                    bucket,
+                },
+                LeakyBucketConfig {
+                    identity: BucketIdentity::IP,
+                    error_kind: BucketErrorKind::UsedExcessiveGas,
+                    bucket: BucketConfig {
+                        retention: Duration::from_secs(100),
+                        ..bucket
+                    },
+                },
+            ],
+            ..Config::default()
+        };
+        let ip1 = BucketNameValue::IP(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        let ip2 = BucketNameValue::IP(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)));
+        let revert = TransactionError::Revert("revert".to_string());
+        let clock = MockClock::default();
+        let mut bh = Banhammer::with_clock(config, clock.shared());
+
+        bh.process_bucket(BucketIdentity::IP, ip1, &input(Some(&revert), 0, 0));
+        bh.tick();
+        assert_eq!(bh.live_buckets(), 2);
+
+        // Recently updated bucket is kept
+        clock.advance(Duration::from_secs(5));
+        bh.process_bucket(BucketIdentity::IP, ip2.clone(), &input(Some(&revert), 0, 0));
+        clock.advance(Duration::from_secs(6));
+        bh.tick();
+        assert_eq!(bh.live_buckets(), 3);
+        let reverts = BucketName::new(BucketIdentity::IP, ip2, BucketErrorKind::Reverts);
+        assert!(bh.leaky_buckets.get(&reverts).is_some());
+
+        clock.advance(Duration::from_secs(10));
+        bh.tick();
+        assert_eq!(bh.live_buckets(), 2);
+
+        clock.advance(Duration::from_secs(100));
+        bh.tick();
+        assert_eq!(bh.live_buckets(), 0);
+        assert!(bh.bucket_pq.keys().is_empty());
+        assert!(bh.next_retention_check.is_empty());
+    }
+
+    #[test]
+    fn test_bucket_limits() {
+        let config = Config {
+            revert_threshold: 100,
+            leaky_buckets: vec![LeakyBucketConfig {
+                identity: BucketIdentity::IP,
+                error_kind: BucketErrorKind::Reverts,
+                bucket: BucketConfig {
+                    base_size: 0,
+                    leak_rate: 1,
+                    overflow_size: None,
+                    retention: Duration::from_secs(3600),
+                    ..BucketConfig::default()
+                },
+            }],
+            bucket_limits: vec![BucketLimit {
+                identity: BucketIdentity::IP,
+                max_buckets: 2,
+            }],
+            ..Config::default()
+        };
+        let ip = |n| BucketNameValue::IP(IpAddr::V4(Ipv4Addr::new(127, 0, 0, n)));
+        let reverts = |n| BucketName::new(BucketIdentity::IP, ip(n), BucketErrorKind::Reverts);
+        let revert = TransactionError::Revert("revert".to_string());
+        let clock = MockClock::default();
+        let mut bh = Banhammer::with_clock(config, clock.shared());
+
+        bh.process_bucket(BucketIdentity::IP, ip(1), &input(Some(&revert), 0, 0));
+        clock.advance(Duration::from_secs(1));
+        bh.process_bucket(BucketIdentity::IP, ip(2), &input(Some(&revert), 0, 0));
+        bh.process_bucket(BucketIdentity::IP, ip(2), &input(Some(&revert), 0, 0));
+        assert_eq!(bh.live_buckets(), 2);
+
+        // Least recently updated bucket is evicted
+        bh.process_bucket(BucketIdentity::IP, ip(3), &input(Some(&revert), 0, 0));
+        assert_eq!(bh.live_buckets(), 2);
+        assert!(bh.leaky_buckets.get(&reverts(1)).is_none());
+
+        // Bucket with the lowest fill is evicted, but
+        // not the bucket of the current message
+        bh.process_bucket(BucketIdentity::IP, ip(3), &input(Some(&revert), 0, 0));
+        bh.process_bucket(BucketIdentity::IP, ip(2), &input(Some(&revert), 0, 0));
+        bh.process_bucket(BucketIdentity::IP, ip(4), &input(Some(&revert), 0, 0));
+        assert_eq!(bh.live_buckets(), 2);
+        assert!(bh.leaky_buckets.get(&reverts(3)).is_none());
+        assert_eq!(bh.leaky_buckets.get(&reverts(2)).unwrap().value, 3);
+        assert_eq!(bh.leaky_buckets.get(&reverts(4)).unwrap().value, 1);
+        assert_eq!(bh.capped_buckets.get(&BucketIdentity::IP), Some(&2));
+        bh.tick();
+        assert!(bh.capped_buckets.is_empty());
+    }
+
+    #[test]
+    fn test_identity_value_limits() {
+        let config = Config {
+            failure_ratio: Some(FailureRatioConfig {
+                max_failure_percent: 50,
+                min_samples: 10,
+                window: Duration::from_secs(3600),
+            }),
+            reputation: Some(ReputationConfig {
+                half_life: Duration::from_secs(3600),
+                retention: Duration::from_secs(3600),
+                weights: Default::default(),
+            }),
+            bucket_limits: vec![BucketLimit {
+                identity: BucketIdentity::IP,
+                max_buckets: 1,
+            }],
+            ..Config::default()
+        };
+        let ip = |n| BucketNameValue::IP(IpAddr::V4(Ipv4Addr::new(127, 0, 0, n)));
+        let clock = MockClock::default();
+        let mut bh = Banhammer::with_clock(config, clock.shared());
+
+        bh.process_bucket(BucketIdentity::IP, ip(1), &input(None, 0, 0));
+        clock.advance(Duration::from_secs(1));
+        bh.process_bucket(BucketIdentity::IP, ip(2), &input(None, 0, 0));
+        assert!(bh.reputation(&ip(1)).is_some());
+        assert!(bh.failure_ratio.get(&ip(1)).is_some());
+
+        // Least recently updated values are evicted on tick
+        bh.tick();
+        assert!(bh.reputation(&ip(1)).is_none());
+        assert!(bh.failure_ratio.get(&ip(1)).is_none());
+        assert!(bh.reputation(&ip(2)).is_some());
+        assert!(bh.failure_ratio.get(&ip(2)).is_some());
+    }
+
+    #[test]
+    fn test_event_time() {
+        let config = Config {
+            revert_threshold: 3,
+            leaky_buckets: vec![LeakyBucketConfig {
+                identity: BucketIdentity::IP,
+                error_kind: BucketErrorKind::Reverts,
+                bucket: BucketConfig {
+                    base_size: 0,
+                    leak_rate: 1,
+                    overflow_size: None,
+                    retention: Duration::from_secs(3600),
+                    period: Duration::from_secs(1),
+                    ..BucketConfig::default()
+                },
+            }],
+            event_time: Some(EventTimeConfig {
+                tolerance: Duration::from_secs(5),
+                idle_timeout: Duration::from_secs(60),
+            }),
+            ..Config::default()
+        };
+        let message = |secs| {
+            let mut message = relayer_message(0);
+            message.timestamp = Timestamp::new(Duration::from_secs(secs));
+            message.error = Some(TransactionError::Revert("revert".to_string()));
+            message
+        };
+        let clock = MockClock::default();
+        let mut bh = Banhammer::with_clock(config.clone(), clock.shared());
+        let mut wall_bh = Banhammer::with_clock(
+            Config {
+                event_time: None,
+                ..config
+            },
+            clock.shared(),
+        );
+
+        // Replay of messages 10 sec apart doesn't overflow bucket
+        for secs in [100, 110, 120, 130] {
+            assert!(bh.read_input(&message(secs)).is_empty());
+        }
+        let events: Vec<_> = [100, 110, 120, 130]
+            .iter()
+            .flat_map(|secs| wall_bh.read_input(&message(*secs)))
+            .collect();
+        assert_eq!(events.len(), 1);
+
+        // Late message is dropped, out-of-order one is processed
+        assert!(bh.read_input(&message(120)).is_empty());
+        let bucket_name = BucketName::new(
+            BucketIdentity::IP,
+            BucketNameValue::IP(relayer_message(0).client),
+            BucketErrorKind::Reverts,
+        );
+        assert_eq!(bh.leaky_buckets.get_fill(&bucket_name, 0), 1);
+        assert_eq!(bh.read_input(&message(126)).len(), 0);
+        assert_eq!(bh.read_input(&message(130)).len(), 1);
+    }
+
+    #[test]
+    fn test_duplicate_bans() {
+        let bucket = BucketConfig {
+            base_size: 0,
+            leak_rate: 1,
+            overflow_size: None,
+            retention: Duration::from_secs(3600),
+            ..BucketConfig::default()
+        };
+        let config = Config {
+            revert_threshold: 2,
+            ban_duration: Duration::from_secs(100),
+            leaky_buckets: vec![
+                LeakyBucketConfig {
+                    identity: BucketIdentity::IP,
+                    error_kind: BucketErrorKind::Reverts,
+                    bucket,
+                },
+                LeakyBucketConfig {
+                    identity: BucketIdentity::Address,
+                    error_kind: BucketErrorKind::Reverts,
+                    bucket,
+                },
+            ],
+            ..Config::default()
+        };
+        let message = |nonce| {
+            let mut message = relayer_message(0);
+            message.error = Some(TransactionError::Revert("revert".to_string()));
+            message.params.eth_nonce = nonce;
+            message
+        };
+        let clock = MockClock::default();
+        let mut bh = Banhammer::with_clock(config, clock.shared());
+
+        // IP and address overflows of one message are one incident
+        assert!(bh.read_input(&message(0)).is_empty());
+        let events = bh.read_input(&message(1));
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].identity, BucketIdentity::IP);
+        assert_eq!(events[1].identity, BucketIdentity::Address);
+        assert!(events[0].incident_id.starts_with(&events[0].instance_id));
+        assert_eq!(events[0].incident_id, events[1].incident_id);
+
+        // Values banned for the same reason aren't banned again
+        for nonce in 2..6 {
+            assert!(bh.read_input(&message(nonce)).is_empty());
+        }
+
+        // Repeat offence after ban expiry
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(bh.tick().len(), 2);
+        assert!(bh.read_input(&message(6)).is_empty());
+        let repeat_events = bh.read_input(&message(7));
+        assert_eq!(repeat_events.len(), 2);
+        assert_eq!(repeat_events[0].duration, 200);
+        assert_ne!(repeat_events[0].incident_id, events[0].incident_id);
+    }
+
+    #[test]
+    fn test_levels() {
+        let config = Config {
+            revert_threshold: 10,
+            leaky_buckets: vec![LeakyBucketConfig {
+                identity: BucketIdentity::IP,
+                error_kind: BucketErrorKind::Reverts,
+                bucket: BucketConfig {
+                    base_size: 0,
+                    leak_rate: 10,
+                    overflow_size: None,
+                    retention: Duration::from_secs(3600),
+                    period: Duration::from_secs(10),
+                    levels: Some(LevelConfig {
+                        warn_percent: Some(60),
+                        throttle_percent: Some(80),
+                    }),
+                    ..BucketConfig::default()
+                },
+            }],
+            ..Config::default()
+        };
+        let revert = TransactionError::Revert("revert".to_string());
+        let ip = BucketNameValue::IP(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        let clock = MockClock::default();
+        let mut bh = Banhammer::with_clock(config, clock.shared());
+        let mut levels = vec![];
+        for _ in 0..12 {
+            let events =
+                bh.process_bucket(BucketIdentity::IP, ip.clone(), &input(Some(&revert), 0, 0));
+            levels.extend(
+                events
+                    .into_iter()
+                    .map(|event| (event.fill, event.level, event.requests_per_second)),
+            );
+        }
+        // Every level is reached once, value isn't banned below overflow
+        assert_eq!(
+            levels,
+            vec![
+                (6, Level::Warn, None),
+                (8, Level::Throttle, Some(1.0)),
+                (10, Level::Ban, None),
+            ]
+        );
+        assert!(bh.bans.is_banned(&ip, bh.clock.now_secs()));
+
+        // Level is lowered as bucket leaks
+        bh.bans.lift(&ip, 0);
+        for _ in 0..6 {
+            bh.process_bucket(BucketIdentity::IP, ip.clone(), &input(Some(&revert), 0, 0));
+        }
+        clock.advance(Duration::from_secs(4));
+        let events = bh.process_bucket(BucketIdentity::IP, ip.clone(), &input(Some(&revert), 0, 0));
+        assert!(events.is_empty());
+        let events = bh.process_bucket(BucketIdentity::IP, ip.clone(), &input(Some(&revert), 0, 0));
+        assert_eq!(events[0].level, Level::Warn);
+        // Warn isn't published as ban
+        assert!(matches!(Event::from(events[0].clone()), Event::Level(_)));
+    }
+
+    #[test]
+    fn test_exemptions() {
+        let config: Config = toml::from_str(
+            r#"
+incorrect_nonce_threshold = 10
+max_gas_threshold = 10
+revert_threshold = 2
+excessive_gas_threshold = 10
+token_multiplier = 1
+exemptions = [
+    { ip = "10.0.0.0/8" },
+    { ip = "192.168.0.0/16", multiplier = 3 },
+]
+
+[[leaky_buckets]]
+identity = "IP"
+error_kind = "Reverts"
+bucket = { base_size = 0, leak_rate = 1, retention = 3600 }
+"#,
+        )
+        .unwrap();
+        let revert = TransactionError::Revert("revert".to_string());
+        let exempted = BucketNameValue::IP(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        let partner = BucketNameValue::IP(IpAddr::V4(Ipv4Addr::new(192, 168, 0, 1)));
+        let mut bh = Banhammer::new(config);
+        for _ in 0..10 {
+            let events = bh.process_bucket(
+                BucketIdentity::IP,
+                exempted.clone(),
+                &input(Some(&revert), 0, 0),
+            );
+            assert!(events.is_empty());
+        }
+        assert_eq!(bh.live_buckets(), 0);
+
+        for _ in 0..5 {
+            let events = bh.process_bucket(
+                BucketIdentity::IP,
+                partner.clone(),
+                &input(Some(&revert), 0, 0),
+            );
+            assert!(events.is_empty());
+        }
+        let events = bh.process_bucket(BucketIdentity::IP, partner, &input(Some(&revert), 0, 0));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].threshold, 6);
+
+        // Exemptions are reloaded with config
+        let mut config = bh.config.clone();
+        config.exemptions = Exemptions::default();
+        bh.update_config(config);
+        bh.process_bucket(
+            BucketIdentity::IP,
+            exempted.clone(),
+            &input(Some(&revert), 0, 0),
+        );
+        let events = bh.process_bucket(BucketIdentity::IP, exempted, &input(Some(&revert), 0, 0));
+        assert_eq!(events.len(), 1);
+    }
+
+    #[test]
+    fn test_subnet_overflow() {
+        let bucket = BucketConfig {
+            base_size: 0,
+            leak_rate: 1,
+            overflow_size: None,
+            retention: Duration::from_secs(10),
+            ..BucketConfig::default()
+        };
+        let config = Config {
+            revert_threshold: 100,
+            leaky_buckets: vec![
+                LeakyBucketConfig {
+                    identity: BucketIdentity::IP,
+                    error_kind: BucketErrorKind::Reverts,
+                    bucket,
+                },
+                LeakyBucketConfig {
+                    identity: BucketIdentity::Subnet,
+                    error_kind: BucketErrorKind::Reverts,
+                    bucket: BucketConfig {
+                        overflow_size: Some(3),
+                        ..bucket
+                    },
+                },
+            ],
+            subnets: Some(SubnetConfig::default()),
+            ..Config::default()
+        };
+        let mut bh = Banhammer::new(config);
+        let clients = ["197.251.253.1", "::ffff:197.251.253.2", "197.251.253.3"];
+        let mut events = vec![];
+        for client in clients {
+            let mut message = relayer_message(0);
+            message.client = client.parse().unwrap();
+            message.error = Some(TransactionError::Revert("revert".to_string()));
+            events = bh.read_input(&message);
+        }
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].identity, BucketIdentity::Subnet);
+        assert_eq!(
+            events[0].value,
+            BucketNameValue::Subnet("197.251.253.0/24".parse().unwrap())
+        );
+        assert_eq!(events[0].threshold, 3);
+    }
+
+    #[test]
+    fn test_subnet_exemption() {
+        let bucket = BucketConfig {
+            base_size: 0,
+            leak_rate: 1,
+            overflow_size: Some(2),
+            retention: Duration::from_secs(10),
+            ..BucketConfig::default()
+        };
+        let config = Config {
+            revert_threshold: 100,
+            leaky_buckets: vec![LeakyBucketConfig {
+                identity: BucketIdentity::Subnet,
+                error_kind: BucketErrorKind::Reverts,
+                bucket,
+            }],
+            subnets: Some(SubnetConfig::default()),
+            exemptions: Exemptions::new(vec![Exemption {
+                ip: Some("197.251.253.1".parse().unwrap()),
+                address: None,
+                token: None,
+                multiplier: None,
+            }]),
+            ..Config::default()
+        };
+        let mut bh = Banhammer::new(config);
+        let mut message = relayer_message(0);
+        message.error = Some(TransactionError::Revert("revert".to_string()));
+        // Exempted IP doesn't fill its subnet bucket
+        message.client = "197.251.253.1".parse().unwrap();
+        for _ in 0..5 {
+            assert!(bh.read_input(&message).is_empty());
+        }
+        assert_eq!(bh.live_buckets(), 0);
+        // Other IPs of the subnet do
+        message.client = "197.251.253.2".parse().unwrap();
+        assert!(bh.read_input(&message).is_empty());
+        let events = bh.read_input(&message);
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].value,
+            BucketNameValue::Subnet("197.251.253.0/24".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_composite_identity() {
+        let config: Config = toml::from_str(
+            r#"
+incorrect_nonce_threshold = 10
+max_gas_threshold = 10
+revert_threshold = 10
+excessive_gas_threshold = 10
+token_multiplier = 1
+
+[[leaky_buckets]]
+identity = { Composite = ["Token", "Address"] }
+error_kind = "Reverts"
+bucket = { base_size = 0, leak_rate = 1, overflow_size = 2, retention = 3600 }
+"#,
+        )
+        .unwrap();
+        let token = Token::new("shared");
+        let message = |from: &str| {
+            let mut message = relayer_message(0);
+            message.params.from = from.parse().unwrap();
+            message.token = Some(token.clone());
+            message.error = Some(TransactionError::Revert("revert".to_string()));
+            message
+        };
+        let address1 = "0xb845796ae42f5061c65717e3e29ff33495b1652d";
+        let address2 = "0x0000000000000000000000000000000000000001";
+        let mut bh = Banhammer::new(config);
+
+        // Token is shared by addresses
+        assert!(bh.read_input(&message(address1)).is_empty());
+        assert!(bh.read_input(&message(address2)).is_empty());
+        let events = bh.read_input(&message(address1));
+        assert_eq!(events.len(), 1);
+        assert_eq!(
+            events[0].identity,
+            BucketIdentity::Composite(vec![BucketIdentity::Token, BucketIdentity::Address])
+        );
+        assert_eq!(
+            events[0].value,
+            BucketNameValue::Composite(vec![
+                BucketNameValue::Token(token.clone()),
+                BucketNameValue::Address(address1.parse().unwrap()),
+            ])
+        );
+        assert!(!bh
+            .bans()
+            .is_banned(&BucketNameValue::Token(token.clone()), bh.clock.now_secs()));
+
+        // Message without token has no composite value
+        let mut no_token = message(address2);
+        no_token.token = None;
+        assert!(bh.read_input(&no_token).is_empty());
+        assert!(bh.read_input(&no_token).is_empty());
+    }
+
+    #[test]
+    fn test_distinct_addresses() {
+        let config = Config {
+            cardinality: Some(CardinalityConfig {
+                max_addresses: 2,
+                window: Duration::from_secs(3600),
+            }),
+            ..Config::default()
+        };
+        let mut bh = Banhammer::new(config);
+        let mut message = relayer_message(0);
+        message.token = Some(Token::new("token"));
+        for i in 1..=2 {
+            message.params.from = Address::from_low_u64_be(i);
+            assert!(bh.read_input(&message).is_empty());
+            assert!(bh.read_input(&message).is_empty());
+        }
+        message.params.from = Address::from_low_u64_be(3);
+        let events = bh.read_input(&message);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].identity, BucketIdentity::IP);
+        assert_eq!(events[1].identity, BucketIdentity::Token);
+        for event in events.iter() {
+            assert_eq!(event.error, BucketErrorKind::DistinctAddresses);
+            assert_eq!(event.fill, 3);
+            assert_eq!(event.threshold, 2);
+        }
+    }
+
+    #[test]
+    fn test_hot_contract() {
+        let bucket = BucketConfig {
+            base_size: 0,
+            leak_rate: 1,
+            overflow_size: None,
+            retention: Duration::from_secs(10),
+            ..BucketConfig::default()
+        };
+        let mut leaky_buckets = vec![];
+        for identity in [BucketIdentity::IP, BucketIdentity::Address] {
+            leaky_buckets.push(LeakyBucketConfig {
+                identity,
+                error_kind: BucketErrorKind::Reverts,
+                bucket,
+            });
+        }
+        leaky_buckets.push(LeakyBucketConfig {
+            identity: BucketIdentity::Contract,
+            error_kind: BucketErrorKind::Reverts,
+            bucket: BucketConfig {
+                overflow_size: Some(3),
+                ..bucket
+            },
+        });
+        let config = Config {
+            revert_threshold: 4,
+            leaky_buckets,
+            ..Config::default()
+        };
+        let contract = Address::from_low_u64_be(100);
+        let message = |sender: u8| {
+            let mut message = relayer_message(0);
+            message.client = IpAddr::V4(Ipv4Addr::new(127, 0, 0, sender));
+            message.params.from = Address::from_low_u64_be(sender as u64);
+            message.params.to = Some(contract);
+            message.error = Some(TransactionError::Revert("revert".to_string()));
+            message
+        };
+        let clock = MockClock::default();
+        let mut bh = Banhammer::with_clock(config.clone(), clock.shared());
+
+        // Contract isn't banned
+        for sender in 1..=3 {
+            assert!(bh.read_input(&message(sender)).is_empty());
+        }
+        assert!(!bh
+            .bans()
+            .is_banned(&BucketNameValue::Contract(contract), bh.clock.now_secs()));
+        let hot_contract_events = bh.take_hot_contract_events();
+        assert_eq!(hot_contract_events.len(), 1);
+        assert_eq!(hot_contract_events[0].contract, contract);
+        assert_eq!(hot_contract_events[0].fill, 3);
+        assert_eq!(
+            hot_contract_events[0].hot_until,
+            config.hot_contracts.duration.as_secs()
+        );
+        assert!(bh.take_hot_contract_events().is_empty());
+
+        // Senders thresholds are halved
+        let events = bh.read_input(&message(1));
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].threshold, 2);
+
+        // Contract cooled down
+        clock.advance(config.hot_contracts.duration);
+        bh.tick();
+        assert!(bh.read_input(&message(2)).is_empty());
+    }
+
+    #[test]
+    fn test_nonce_gap() {
+        let bucket = BucketConfig {
+            base_size: 0,
+            leak_rate: 1,
+            overflow_size: None,
+            retention: Duration::from_secs(10),
+            ..BucketConfig::default()
+        };
+        let mut leaky_buckets = vec![];
+        for error_kind in [
+            BucketErrorKind::IncorrectNonce,
+            BucketErrorKind::NonceTooLow,
+            BucketErrorKind::NonceTooHigh,
+        ] {
+            leaky_buckets.push(LeakyBucketConfig {
+                identity: BucketIdentity::Address,
+                error_kind,
+                bucket,
+            });
+        }
+        let config = Config {
+            incorrect_nonce_threshold: 2,
+            leaky_buckets,
+            nonces: Some(NonceConfig {
+                tolerance: 1,
+                retention: Duration::from_secs(3600),
+            }),
+            ..Config::default()
+        };
+        let message = |nonce: u32, error: Option<TransactionError>| {
+            let mut message = relayer_message(0);
+            message.params.eth_nonce = nonce;
+            message.error = error;
+            message
+        };
+        let mut bh = Banhammer::new(config);
+
+        // Unknown address falls back to incorrect nonce bucket
+        let events = bh.read_input(&message(5, Some(TransactionError::ErrIncorrectNonce)));
+        assert!(events.is_empty());
+
+        // Wallet behind by one nonce isn't punished
+        assert!(bh.read_input(&message(10, None)).is_empty());
+        for _ in 0..5 {
+            let events = bh.read_input(&message(10, Some(TransactionError::ErrIncorrectNonce)));
+            assert!(events.is_empty());
+        }
+
+        // Replays
+        assert!(bh
+            .read_input(&message(3, Some(TransactionError::ErrIncorrectNonce)))
+            .is_empty());
+        let events = bh.read_input(&message(4, Some(TransactionError::ErrIncorrectNonce)));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].error, BucketErrorKind::NonceTooLow);
+
+        // Queue flooding
+        assert!(bh
+            .read_input(&message(30, Some(TransactionError::ErrIncorrectNonce)))
+            .is_empty());
+        let events = bh.read_input(&message(31, Some(TransactionError::ErrIncorrectNonce)));
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].error, BucketErrorKind::NonceTooHigh);
+    }
+
+    #[test]
+    fn test_method_policies() {
+        let config: Config = toml::from_str(
+            r#"
+incorrect_nonce_threshold = 10
+max_gas_threshold = 10
+revert_threshold = 10
+excessive_gas_threshold = 1000
+token_multiplier = 1
+
+[[leaky_buckets]]
+identity = "IP"
+error_kind = "UsedExcessiveGas"
+bucket = { base_size = 0, leak_rate = 1, retention = 10 }
+
+[[methods]]
+method = "eth_sendRawTransaction"
+weight = 2
+
+[[methods]]
+method = "eth_estimateGas"
+[[methods.leaky_buckets]]
+identity = "IP"
+error_kind = "UsedExcessiveGas"
+bucket = { base_size = 0, leak_rate = 1, overflow_size = 100, retention = 100 }
+"#,
+        )
+        .unwrap();
+        let mut bh = Banhammer::new(config);
+        assert_eq!(
+            bh.config
+                .get_retention(&BucketIdentity::IP, &BucketErrorKind::UsedExcessiveGas),
+            Some(Duration::from_secs(100))
+        );
+
+        // Not listed method isn't evaluated
+        let mut message = relayer_message(400_000_000_000_000);
+        message.method = "eth_getBalance".to_string();
+        for _ in 0..10 {
+            assert!(bh.read_input(&message).is_empty());
+        }
+        assert_eq!(bh.live_buckets(), 0);
+
+        // Fill is doubled for sent transactions, 400 Tgas * 2 per message
+        message.method = "eth_sendrawtransaction".to_string();
+        assert!(bh.read_input(&message).is_empty());
+        let events = bh.read_input(&message);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].fill, 1_600_000_000_000_000);
+
+        // Method bucket config overrides common threshold
+        message.client = "127.0.0.1".parse().unwrap();
+        message.method = "ETH_ESTIMATEGAS".to_string();
+        let events = bh.read_input(&message);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].threshold, 100_000_000_000_000);
+    }
+
+    #[test]
+    fn test_token_tiers() {
+        let config: Config = toml::from_str(
+            r#"
+incorrect_nonce_threshold = 10
+max_gas_threshold = 10
+revert_threshold = 10
+excessive_gas_threshold = 1000
+token_multiplier = 100
+
+[[leaky_buckets]]
+identity = "Token"
+error_kind = "UsedExcessiveGas"
+bucket = { base_size = 0, leak_rate = 1, retention = 10 }
+
+[token_tiers]
+unknown_tier = "anonymous"
+
+[[token_tiers.tiers]]
+name = "anonymous"
+
+[[token_tiers.tiers]]
+name = "free"
+multiplier = 2
+
+[[token_tiers.tiers]]
+name = "enterprise"
+[[token_tiers.tiers.leaky_buckets]]
+identity = "Token"
+error_kind = "UsedExcessiveGas"
+bucket = { base_size = 0, leak_rate = 1, overflow_size = 5000, retention = 100 }
+"#,
+        )
+        .unwrap();
+        let free = Token::new("Zm9vYmFyZm9vYmFyZm9vYmFyZm9vYmFyZm9vYmFyZm9v");
+        let partner = Token::new("YmF6YmF6YmF6YmF6YmF6YmF6YmF6YmF6YmF6YmF6YmF6");
+        let mut tokens = HashMap::new();
+        tokens.insert(free.as_str().to_string(), "free".to_string());
+        tokens.insert(partner.as_str().to_string(), "enterprise".to_string());
+        let mut bh = Banhammer::new(config);
+        bh.update_token_registry(TokenRegistry::new(tokens));
+
+        let threshold = |bh: &mut Banhammer, token: &Token| {
+            let mut message = relayer_message(2_000_000_000_000_000);
+            message.token = Some(token.clone());
+            bh.read_input(&message)
+                .iter()
+                .find(|event| event.identity == BucketIdentity::Token)
+                .map(|event| event.threshold)
+        };
+        // Tier multiplier instead of `token_multiplier`
+        assert_eq!(threshold(&mut bh, &free), Some(2_000_000_000_000_000));
+        // Tier bucket config is used as is
+        assert_eq!(threshold(&mut bh, &partner), None);
+        // Unknown and malformed tokens get unknown tier
+        assert_eq!(
+            threshold(
+                &mut bh,
+                &Token::new("cXV4cXV4cXV4cXV4cXV4cXV4cXV4cXV4cXV4cXV4cXV4")
+            ),
+            Some(1_000_000_000_000_000)
+        );
+        assert_eq!(
+            threshold(&mut bh, &Token::new("partner")),
+            Some(1_000_000_000_000_000)
+        );
+        // Big tier multiplier saturates threshold
+        let config = Config {
+            excessive_gas_threshold: 1000,
+            ..Config::default()
+        };
+        assert_eq!(
+            config.threshold(None, &BucketErrorKind::UsedExcessiveGas, u64::MAX),
+            Some(u64::MAX)
+        );
+    }
+
+    #[test]
+    fn test_reputation_rule() {
+        let config: Config = toml::from_str(
+            r#"
+incorrect_nonce_threshold = 10
+max_gas_threshold = 10
+revert_threshold = 10
+excessive_gas_threshold = 10
+token_multiplier = 1
+
+[reputation]
+half_life = 100
+retention = 1000
+
+[[leaky_buckets]]
+identity = "IP"
+error_kind = "Reverts"
+bucket = { base_size = 0, leak_rate = 3600, retention = 3600 }
+
+[[rules]]
+name = "reverts"
+identities = ["IP"]
+error_kind = "Reverts"
+predicate = { error = ["Revert"] }
+
+# Poor reputation fills reverts bucket faster
+[[rules]]
+name = "reverts_poor_reputation"
+identities = ["IP"]
+weight = 2
+error_kind = "Reverts"
+predicate = { error = ["Revert"], reputation = { max = -10.0 } }
+"#,
+        )
+        .unwrap();
+        let mut message = relayer_message(0);
+        message.error = Some(TransactionError::Revert("revert".to_string()));
+        let ip = BucketNameValue::IP(message.client);
+        let clock = MockClock::default();
+        let mut bh = Banhammer::with_clock(config, clock.shared());
+
+        // Reputation -5 and -10 after 2 reverts, bucket fill 1 and 4
+        for _ in 0..3 {
+            assert!(bh.read_input(&message).is_empty());
+        }
+        assert_eq!(bh.reputation(&ip), Some(-15.0));
+        let events = bh.read_input(&message);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].fill, 10);
+
+        clock.advance(Duration::from_secs(100));
+        assert_eq!(bh.reputation(&ip), Some(-10.0));
+        assert_eq!(
+            bh.reputation(&BucketNameValue::Address(message.params.from)),
+            Some(-10.0)
+        );
+    }
+
+    #[test]
+    fn test_shadow_metrics() {
+        let config: Config = toml::from_str(
+            r#"
+incorrect_nonce_threshold = 10
+max_gas_threshold = 10
+revert_threshold = 10
+excessive_gas_threshold = 10
+token_multiplier = 1
+
+[[leaky_buckets]]
+identity = "IP"
+error_kind = { Custom = "shadow" }
+bucket = { base_size = 0, leak_rate = 1, overflow_size = 1, retention = 3600 }
+
+[[bucket_limits]]
+identity = "IP"
+max_buckets = 1
+
+[[rules]]
+name = "shadow"
+identities = ["IP"]
+error_kind = { Custom = "shadow" }
+"#,
+        )
+        .unwrap();
+        let ip = |n| BucketNameValue::IP(IpAddr::V4(Ipv4Addr::new(127, 0, 0, n)));
+        let reason = BucketErrorKind::Custom("shadow".into());
+        let mut bh = Banhammer::new(config.clone());
+        bh.set_metrics(ShadowMetrics::shared());
+
+        // Duplicate ban and capped bucket of candidate config
+        assert_eq!(
+            bh.process_bucket(BucketIdentity::IP, ip(1), &input(None, 0, 0))
+                .len(),
+            1
+        );
+        assert!(bh
+            .process_bucket(BucketIdentity::IP, ip(1), &input(None, 0, 0))
+            .is_empty());
+        bh.process_bucket(BucketIdentity::IP, ip(2), &input(None, 0, 0));
+        bh.tick();
+        assert_eq!(
+            shadow_series().suppressed_bans(&BucketIdentity::IP, &reason),
+            1
+        );
+        assert_eq!(shadow_series().capped_buckets(&BucketIdentity::IP), 1);
+        // Live series are left unchanged
+        assert_eq!(
+            live_series().suppressed_bans(&BucketIdentity::IP, &reason),
+            0
+        );
+
+        let mut bh = Banhammer::new(config);
+        bh.process_bucket(BucketIdentity::IP, ip(1), &input(None, 0, 0));
+        bh.process_bucket(BucketIdentity::IP, ip(1), &input(None, 0, 0));
+        assert_eq!(
+            live_series().suppressed_bans(&BucketIdentity::IP, &reason),
+            1
+        );
+        assert_eq!(
+            shadow_series().suppressed_bans(&BucketIdentity::IP, &reason),
+            1
+        );
     }
 }
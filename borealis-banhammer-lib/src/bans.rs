@@ -0,0 +1,325 @@
+//! # Bans
+//!
+//! Registry of active bans. Ban has expiry time and
+//! ban duration grows exponentially for repeat offenders
+//! within memory window:
+//!
+//! duration = ban_duration * escalation_factor ^ (offences - 1)
+//!
+//! Duration is limited by `max_duration`.
+use crate::buckets::{BucketErrorKind, BucketIdentity, BucketNameValue};
+use crate::de::{deserialize_duration, serialize_duration};
+use crate::events::{UnbanEvent, UnbanReason};
+use crate::limits::evict_least_recent;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+
+/// Ban lifecycle config
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct BanConfig {
+    /// Ban duration multiplier for each repeat offence
+    pub escalation_factor: u32,
+    /// Max ban duration in seconds
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub max_duration: Duration,
+    /// How long offences are remembered after the last ban, in seconds
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub memory: Duration,
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        Self {
+            escalation_factor: 2,
+            max_duration: Duration::from_secs(7 * 86400),
+            memory: Duration::from_secs(86400),
+        }
+    }
+}
+
+/// Active ban data
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActiveBan {
+    pub identity: BucketIdentity,
+    pub reason: BucketErrorKind,
+    /// All error kinds the value is banned for
+    pub reasons: Vec<BucketErrorKind>,
+    /// Ban UNIX time in sec
+    pub banned_at: u64,
+    /// Ban expiry UNIX time in sec
+    pub expires_at: u64,
+    /// Offences count within memory window
+    pub offences: u32,
+}
+
+/// Offences history for identity value
+#[derive(Debug, Clone)]
+struct Offender {
+    offences: u32,
+    last_ban: u64,
+}
+
+/// Ban registry represent active bans and
+/// offences history by bucket name value
+#[derive(Default)]
+pub struct BanRegistry {
+    active: HashMap<BucketNameValue, ActiveBan>,
+    history: HashMap<BucketNameValue, Offender>,
+}
+
+impl BanRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register ban for identity value and return ban duration in sec.
+    /// Repeat offence within memory window escalates ban duration.
+    /// Ban for actively banned value, e.g. other bucket overflowed
+    /// by the same message, isn't an offence: ban is extended
+    /// with duration of the current offences count.
+    pub fn ban(
+        &mut self,
+        identity: &BucketIdentity,
+        value: &BucketNameValue,
+        reason: &BucketErrorKind,
+        now: u64,
+        ban_duration: Duration,
+        config: &BanConfig,
+    ) -> u64 {
+        let banned = matches!(self.active.get(value), Some(ban) if ban.expires_at > now);
+        let offender = self.history.entry(value.clone()).or_insert(Offender {
+            offences: 0,
+            last_ban: now,
+        });
+        if !banned || offender.offences == 0 {
+            if now.saturating_sub(offender.last_ban) > config.memory.as_secs() {
+                offender.offences = 0;
+            }
+            offender.offences = offender.offences.saturating_add(1);
+        }
+        offender.last_ban = now;
+
+        let duration = Self::escalate(offender.offences, ban_duration, config);
+        let offences = offender.offences;
+        let expires_at = now.saturating_add(duration);
+        let ban = self.active.entry(value.clone()).or_insert(ActiveBan {
+            identity: identity.clone(),
+            reason: reason.clone(),
+            reasons: vec![],
+            banned_at: now,
+            expires_at,
+            offences,
+        });
+        ban.reason = reason.clone();
+        if !ban.reasons.contains(reason) {
+            ban.reasons.push(reason.clone());
+        }
+        ban.expires_at = ban.expires_at.max(expires_at);
+        ban.offences = offences;
+        duration
+    }
+
+    /// Ban duration for offences count
+    fn escalate(offences: u32, ban_duration: Duration, config: &BanConfig) -> u64 {
+        let max_duration = config.max_duration.as_secs();
+        let factor = (config.escalation_factor.max(1) as u64)
+            .checked_pow(offences.saturating_sub(1))
+            .unwrap_or(u64::MAX);
+        ban_duration
+            .as_secs()
+            .saturating_mul(factor)
+            .min(max_duration)
+    }
+
+    /// Is identity value banned now, expired ban
+    /// isn't counted before it's swept
+    pub fn is_banned(&self, value: &BucketNameValue, now: u64) -> bool {
+        matches!(self.active.get(value), Some(ban) if ban.expires_at > now)
+    }
+
+    /// Is identity value banned now for the error kind
+    pub fn is_banned_for(
+        &self,
+        value: &BucketNameValue,
+        reason: &BucketErrorKind,
+        now: u64,
+    ) -> bool {
+        matches!(
+            self.active.get(value),
+            Some(ban) if ban.expires_at > now && ban.reasons.contains(reason)
+        )
+    }
+
+    /// Get active ban for identity value
+    pub fn get(&self, value: &BucketNameValue) -> Option<&ActiveBan> {
+        self.active.get(value)
+    }
+
+    /// Active bans count
+    pub fn len(&self) -> usize {
+        self.active.len()
+    }
+
+    /// Is there no active bans
+    pub fn is_empty(&self) -> bool {
+        self.active.is_empty()
+    }
+
+    /// Remove expired bans and forget offenders out of memory window.
+    /// Return unban events for expired bans
+    pub fn expire(&mut self, now: u64, config: &BanConfig) -> Vec<UnbanEvent> {
+        let expired: Vec<BucketNameValue> = self
+            .active
+            .iter()
+            .filter(|(_, ban)| ban.expires_at <= now)
+            .map(|(value, _)| value.clone())
+            .collect();
+        let unban_events = expired
+            .into_iter()
+            .filter_map(|value| self.unban(&value, now, UnbanReason::Expired))
+            .collect();
+
+        let memory = config.memory.as_secs();
+        let active = &self.active;
+        self.history.retain(|value, offender| {
+            active.contains_key(value) || now.saturating_sub(offender.last_ban) <= memory
+        });
+        unban_events
+    }
+
+    /// Forget offences of identity kind values over `max`, least recently
+    /// banned first. Active bans are enforced, so they are never evicted.
+    /// Return: forgotten offenders count
+    pub fn limit(&mut self, kind: &BucketIdentity, max: usize) -> usize {
+        let active = &self.active;
+        evict_least_recent(
+            &mut self.history,
+            max,
+            |value, _| &value.identity() == kind && !active.contains_key(value),
+            |offender| offender.last_ban,
+        )
+    }
+
+    /// Lift ban manually before expiry
+    pub fn lift(&mut self, value: &BucketNameValue, now: u64) -> Option<UnbanEvent> {
+        self.unban(value, now, UnbanReason::Lifted)
+    }
+
+    fn unban(
+        &mut self,
+        value: &BucketNameValue,
+        now: u64,
+        reason: UnbanReason,
+    ) -> Option<UnbanEvent> {
+        self.active.remove(value).map(|ban| UnbanEvent {
+            identity: ban.identity,
+            value: value.clone(),
+            error: ban.reason,
+            reason,
+            banned_at: ban.banned_at,
+            unbanned_at: now,
+            offences: ban.offences,
+            instance_id: String::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn ban(registry: &mut BanRegistry, value: &BucketNameValue, now: u64) -> u64 {
+        registry.ban(
+            &BucketIdentity::IP,
+            value,
+            &BucketErrorKind::Reverts,
+            now,
+            Duration::from_secs(100),
+            &BanConfig {
+                escalation_factor: 2,
+                max_duration: Duration::from_secs(1000),
+                memory: Duration::from_secs(10000),
+            },
+        )
+    }
+
+    #[test]
+    fn test_escalation() {
+        let value = BucketNameValue::IP(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        let mut registry = BanRegistry::default();
+
+        assert_eq!(ban(&mut registry, &value, 0), 100);
+        assert!(registry.is_banned(&value, 99));
+        assert!(!registry.is_banned(&value, 100));
+        assert_eq!(registry.get(&value).unwrap().expires_at, 100);
+        assert!(registry.is_banned_for(&value, &BucketErrorKind::Reverts, 99));
+        assert!(!registry.is_banned_for(&value, &BucketErrorKind::Reverts, 100));
+        assert!(!registry.is_banned_for(&value, &BucketErrorKind::MaxGas, 99));
+        assert_eq!(ban(&mut registry, &value, 200), 200);
+        assert_eq!(ban(&mut registry, &value, 400), 400);
+        assert_eq!(ban(&mut registry, &value, 800), 800);
+        // Max duration
+        assert_eq!(ban(&mut registry, &value, 1600), 1000);
+        assert_eq!(registry.get(&value).unwrap().offences, 5);
+
+        // Out of memory window
+        assert_eq!(ban(&mut registry, &value, 20000), 100);
+        assert_eq!(registry.get(&value).unwrap().offences, 1);
+    }
+
+    #[test]
+    fn test_active_ban_isnt_escalated() {
+        let value = BucketNameValue::IP(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        let config = BanConfig::default();
+        let mut registry = BanRegistry::default();
+        assert_eq!(ban(&mut registry, &value, 0), 100);
+        // Other bucket overflowed by the same message
+        let duration = registry.ban(
+            &BucketIdentity::IP,
+            &value,
+            &BucketErrorKind::UsedExcessiveGas,
+            0,
+            Duration::from_secs(100),
+            &config,
+        );
+        assert_eq!(duration, 100);
+        let active = registry.get(&value).unwrap();
+        assert_eq!(active.offences, 1);
+        assert_eq!(active.expires_at, 100);
+        assert_eq!(active.reasons.len(), 2);
+
+        // Repeat offence after expiry
+        assert_eq!(registry.expire(100, &config).len(), 1);
+        assert_eq!(ban(&mut registry, &value, 150), 200);
+    }
+
+    #[test]
+    fn test_expire_and_lift() {
+        let value1 = BucketNameValue::IP(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        let value2 = BucketNameValue::IP(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2)));
+        let config = BanConfig::default();
+        let mut registry = BanRegistry::default();
+        ban(&mut registry, &value1, 0);
+        ban(&mut registry, &value2, 50);
+
+        assert!(registry.expire(99, &config).is_empty());
+        let events = registry.expire(100, &config);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].value, value1);
+        assert_eq!(events[0].reason, UnbanReason::Expired);
+        assert!(!registry.is_banned(&value1, 100));
+
+        let event = registry.lift(&value2, 120).unwrap();
+        assert_eq!(event.reason, UnbanReason::Lifted);
+        assert_eq!(event.banned_at, 50);
+        assert!(registry.is_empty());
+        assert!(registry.lift(&value2, 130).is_none());
+    }
+}
@@ -1,7 +1,9 @@
 use borealis_banhammer_lib::{
     banhammer::{self, Banhammer},
     de::RelayerMessage,
-    stats::{Counter, Measure},
+    levels::Level,
+    stats::{Counter, Measure, ShadowMetrics},
+    tiers::TokenRegistry,
 };
 use hyper::{
     header::CONTENT_TYPE,
@@ -9,7 +11,7 @@ use hyper::{
     Body, Request, Response, Server,
 };
 use prometheus::Encoder;
-use std::{fs, io, time::Instant};
+use std::{fs, io};
 use tokio::join;
 use tracing::{debug, error, info};
 
@@ -40,9 +42,20 @@ async fn serve() {
 }
 
 /// Process leaky buckets ban
-async fn process(ban_manager_config: banhammer::Config) {
+async fn process(
+    ban_manager_config: banhammer::Config,
+    shadow_config: Option<banhammer::Config>,
+    tokens: TokenRegistry,
+) {
     let mut ban_manager = Banhammer::new(ban_manager_config);
-    let time = Instant::now();
+    ban_manager.update_token_registry(tokens.clone());
+    // Candidate config evaluated in shadow mode, nobody is banned by it
+    let mut shadow_ban_manager = shadow_config.map(|shadow_config| {
+        let mut shadow_ban_manager = Banhammer::new(shadow_config);
+        shadow_ban_manager.update_token_registry(tokens);
+        shadow_ban_manager.set_metrics(ShadowMetrics::shared());
+        shadow_ban_manager
+    });
 
     info!("Starting banhammer...");
     loop {
@@ -68,17 +81,51 @@ async fn process(ban_manager_config: banhammer::Config) {
         for ban_event in ban_events {
             info!("Ban event: {:?}", ban_event);
             Measure::inc(Counter::MessagesSent);
-            Measure::inc(Counter::BanReason(ban_event.clone()));
+            Measure::inc(Counter::BanLevel(ban_event.level));
+            if let Level::Ban = ban_event.level {
+                Measure::inc(Counter::BanReason(
+                    ban_event.identity.clone(),
+                    ban_event.error.clone(),
+                ));
+            }
+        }
+        for hot_contract_event in ban_manager.take_hot_contract_events() {
+            info!("Hot contract event: {:?}", hot_contract_event);
+            Measure::inc(Counter::MessagesSent);
+            Measure::inc(Counter::HotContracts);
         }
         Measure::inc(Counter::MessagesProcessed);
 
-        ban_manager.tick(time);
+        for unban_event in ban_manager.tick() {
+            info!("Unban event: {:?}", unban_event);
+            Measure::inc(Counter::MessagesSent);
+            Measure::inc(Counter::Unbans);
+        }
+
+        if let Some(shadow_ban_manager) = shadow_ban_manager.as_mut() {
+            for would_ban_event in shadow_ban_manager.read_input(&relayer_input) {
+                info!("Would-ban event: {:?}", would_ban_event);
+                Measure::inc(Counter::ShadowMessagesSent);
+                if let Level::Ban = would_ban_event.level {
+                    Measure::inc(Counter::WouldBanReason(
+                        would_ban_event.identity,
+                        would_ban_event.error,
+                    ));
+                }
+            }
+            shadow_ban_manager.take_hot_contract_events();
+            shadow_ban_manager.tick();
+        }
     }
 }
 
 /// Handle all asyc tasks
-async fn handle(ban_manager_config: banhammer::Config) {
-    join!(serve(), process(ban_manager_config));
+async fn handle(
+    ban_manager_config: banhammer::Config,
+    shadow_config: Option<banhammer::Config>,
+    tokens: TokenRegistry,
+) {
+    join!(serve(), process(ban_manager_config, shadow_config, tokens));
 }
 
 #[tokio::main]
@@ -89,5 +136,15 @@ async fn main() {
     let ban_manager_config: banhammer::Config =
         toml::from_str(&raw_toml).expect("Failed to parse TOML.");
 
-    handle(ban_manager_config).await;
+    // Candidate config for shadow (dry-run) evaluation is optional
+    let shadow_config: Option<banhammer::Config> = fs::read_to_string("./ShadowConfig.toml")
+        .ok()
+        .map(|raw_toml| toml::from_str(&raw_toml).expect("Failed to parse shadow TOML."));
+
+    // Token registry for token tiers is optional
+    let tokens: TokenRegistry = fs::read_to_string("./Tokens.toml")
+        .map(|raw_toml| toml::from_str(&raw_toml).expect("Failed to parse tokens TOML."))
+        .unwrap_or_default();
+
+    handle(ban_manager_config, shadow_config, tokens).await;
 }
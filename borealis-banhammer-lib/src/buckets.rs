@@ -1,17 +1,25 @@
 //! # Buckets
 //!
 //! Contains Buckets and Leaky buckets logic
-use crate::de::deserialize_duration;
+use crate::clock::{SharedClock, SystemClock};
 use crate::de::Token;
+use crate::de::{deserialize_duration, serialize_duration};
+use crate::levels::{Level, LevelConfig};
+use crate::limiters::Algorithm;
+use crate::subnet::Cidr;
 use ethereum_types::Address;
 use priority_queue::PriorityQueue;
 use serde::{Deserialize, Serialize};
-use std::{cmp::Reverse, collections::HashMap, net::IpAddr, time::Duration, time::SystemTime};
+use std::{cmp::Reverse, collections::HashMap, net::IpAddr, time::Duration};
 
 /// Bucket priority queue where:
 /// - key: bucket name
-/// - value: last bucket update
-pub struct BucketPriorityQueue(PriorityQueue<BucketName, Reverse<u64>>);
+/// - value: last bucket update and bucket fill,
+///   least recently updated and lowest fill first
+pub struct BucketPriorityQueue {
+    queue: PriorityQueue<BucketName, Reverse<(u64, BucketValue)>>,
+    clock: SharedClock,
+}
 
 impl Default for BucketPriorityQueue {
     fn default() -> Self {
@@ -21,50 +29,62 @@ impl Default for BucketPriorityQueue {
 
 impl BucketPriorityQueue {
     pub fn new() -> Self {
-        Self(PriorityQueue::new())
+        Self::with_clock(SystemClock::shared())
     }
 
-    /// Get current time without panic
-    pub fn current_time() -> u64 {
-        match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-            Ok(elapsed) => elapsed.as_secs(),
-            Err(e) => {
-                tracing::error!("{e:?}");
-                0
-            }
+    pub fn with_clock(clock: SharedClock) -> Self {
+        Self {
+            queue: PriorityQueue::new(),
+            clock,
         }
     }
 
     ///  Returns the couple (item, priority) with the greatest priority
     /// in the queue, or None if it is empty.
-    pub fn peek(&self) -> Option<(&BucketName, &Reverse<u64>)> {
-        self.0.peek()
+    pub fn peek(&self) -> Option<(&BucketName, &Reverse<(u64, BucketValue)>)> {
+        self.queue.peek()
     }
 
     /// Removes the item with the greatest priority from the priority
     /// queue and returns the pair (item, priority), or None if the queue is empty.
-    pub fn pop(&mut self) -> Option<(BucketName, Reverse<u64>)> {
-        self.0.pop()
+    pub fn pop(&mut self) -> Option<(BucketName, Reverse<(u64, BucketValue)>)> {
+        self.queue.pop()
     }
 
     /// Insert the item-priority pair into the queue.
     /// If an element equal to item was already into the queue, it is updated and the old value of its priority returned in Some; otherwise, returns None.
     pub fn push(&mut self, bucket_name: BucketName) {
-        self.0.push(bucket_name, Reverse(Self::current_time()));
+        self.push_fill(bucket_name, 0);
+    }
+
+    /// Insert the item with current time and bucket fill priority
+    pub fn push_fill(&mut self, bucket_name: BucketName, fill: BucketValue) {
+        self.queue
+            .push(bucket_name, Reverse((self.clock.now_secs(), fill)));
+    }
+
+    /// Queued buckets count
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
     }
 
     /// Remove from prioirty queue
     pub fn remove(&mut self, bucket_name: &BucketName) {
-        self.0.remove(bucket_name);
+        self.queue.remove(bucket_name);
     }
 
     /// Pop from priority queue data that should be freed
     /// and return Buckets list
     pub fn retention_free(&mut self, retention_time: u64) -> Vec<BucketName> {
         let mut buckets = vec![];
+        let current_time = self.clock.now_secs();
         // Check priority queue and if it's ready pop it
         while let Some((bucket_name, value)) = self.peek() {
-            if Self::current_time() - value.0 > retention_time {
+            if current_time.saturating_sub(value.0 .0) > retention_time {
                 buckets.push(bucket_name.clone());
                 // Remove from queue
                 self.pop();
@@ -82,29 +102,63 @@ pub enum BucketIdentity {
     IP,
     Address,
     Token,
+    /// Client IP subnet
+    Subnet,
+    /// Transaction target contract
+    Contract,
+    /// Combination of identities, e.g. (IP, Address)
+    Composite(Vec<BucketIdentity>),
 }
 
 /// Bucket name value - specific value for Identity
-#[derive(Debug, Hash, Clone, Eq, PartialEq)]
+#[derive(Debug, Hash, Clone, Eq, PartialEq, Serialize)]
 pub enum BucketNameValue {
     IP(IpAddr),
     Address(Address),
     Token(Token),
+    Subnet(Cidr),
+    Contract(Address),
+    /// Values of composite identity parts
+    Composite(Vec<BucketNameValue>),
+}
+
+impl BucketNameValue {
+    /// Identity kind of value
+    pub fn identity(&self) -> BucketIdentity {
+        match self {
+            Self::IP(_) => BucketIdentity::IP,
+            Self::Address(_) => BucketIdentity::Address,
+            Self::Token(_) => BucketIdentity::Token,
+            Self::Subnet(_) => BucketIdentity::Subnet,
+            Self::Contract(_) => BucketIdentity::Contract,
+            Self::Composite(values) => {
+                BucketIdentity::Composite(values.iter().map(Self::identity).collect())
+            }
+        }
+    }
 }
 
 /// BUcket error kind - basic errors for ban event
 #[derive(Debug, Hash, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub enum BucketErrorKind {
     IncorrectNonce,
+    /// Nonce below expected: replays or resubmits
+    NonceTooLow,
+    /// Nonce above expected: queue flooding
+    NonceTooHigh,
     MaxGas,
     Reverts,
     UsedExcessiveGas,
+    UsedExcessiveEvmGas,
+    FailureRatio,
+    /// Too many distinct sender addresses
+    DistinctAddresses,
     Custom(String),
 }
 
 /// Bucket name represent bucket itself
 /// Bucket is: bucket_name => bucket_data
-#[derive(Debug, Hash, Clone, Eq, PartialEq)]
+#[derive(Debug, Hash, Clone, Eq, PartialEq, Serialize)]
 pub struct BucketName {
     kind: BucketIdentity,
     value: BucketNameValue,
@@ -123,32 +177,107 @@ impl BucketName {
     pub fn identity(&self) -> BucketIdentity {
         self.kind.clone()
     }
+
+    pub fn value(&self) -> BucketNameValue {
+        self.value.clone()
+    }
 }
 
 /// Bucket value - basic type for all buckets
 pub type BucketValue = u64;
 
 /// Bucket data contain value kind
-/// for specific bucket, and last bucket
-/// update UNIX time in sec
+/// for specific bucket, last bucket
+/// update UNIX time in sec, and relayer
+/// timestamps (in ms) of first and last
+/// offending messages since last overflow
 #[derive(Debug, Hash, Clone)]
 pub struct BucketData {
     pub value: BucketValue,
     pub last_update: u64,
+    pub first_offence: Option<u64>,
+    pub last_offence: Option<u64>,
+    /// Rate limiter time: last leak, window start
+    /// or theoretical arrival time for GCRA
+    pub updated_at: Duration,
+    /// Previous window value for sliding window
+    pub previous: BucketValue,
+    /// Level of fill below overflow
+    pub level: Option<Level>,
+}
+
+impl BucketData {
+    /// Empty bucket
+    pub fn new(now: Duration) -> Self {
+        Self {
+            value: 0,
+            last_update: now.as_secs(),
+            first_offence: None,
+            last_offence: None,
+            updated_at: now,
+            previous: 0,
+            level: None,
+        }
+    }
 }
 
 /// Leaky bucket represent Map of key-value of
 /// bucket name & bucket_data
-pub struct LeakyBucket(HashMap<BucketName, BucketData>);
+pub struct LeakyBucket {
+    buckets: HashMap<BucketName, BucketData>,
+    clock: SharedClock,
+}
 
 /// Basic bucket config
-#[derive(Debug, Serialize, Deserialize, Copy, Clone)]
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq)]
 pub struct BucketConfig {
     pub base_size: u64,
+    /// Leak rate per `period`
     pub leak_rate: u64,
-    pub overflow_size: u64,
-    #[serde(deserialize_with = "deserialize_duration")]
+    /// Overflow threshold, global threshold for error kind is used if not set
+    #[serde(default)]
+    pub overflow_size: Option<u64>,
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
     pub retention: Duration,
+    /// Rate limiter algorithm, leaky bucket by default
+    #[serde(default = "default_algorithm")]
+    pub algorithm: Algorithm,
+    /// Leak rate period and sliding window size in seconds,
+    /// fractions of second are allowed. Default is 1 day
+    #[serde(
+        default = "default_period",
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub period: Duration,
+    /// Warn and throttle levels below overflow, only ban if not set
+    #[serde(default)]
+    pub levels: Option<LevelConfig>,
+}
+
+fn default_algorithm() -> Algorithm {
+    Algorithm::LeakyBucket
+}
+
+fn default_period() -> Duration {
+    Duration::from_secs(86400)
+}
+
+impl Default for BucketConfig {
+    fn default() -> Self {
+        Self {
+            base_size: 0,
+            leak_rate: 0,
+            overflow_size: None,
+            retention: Duration::default(),
+            algorithm: default_algorithm(),
+            period: default_period(),
+            levels: None,
+        }
+    }
 }
 
 impl Default for LeakyBucket {
@@ -159,12 +288,19 @@ impl Default for LeakyBucket {
 
 impl LeakyBucket {
     pub fn new() -> Self {
-        Self(HashMap::new())
+        Self::with_clock(SystemClock::shared())
+    }
+
+    pub fn with_clock(clock: SharedClock) -> Self {
+        Self {
+            buckets: HashMap::new(),
+            clock,
+        }
     }
 
     /// Calculate new fill value
     pub fn get_fill(&self, key: &BucketName, value: BucketValue) -> BucketValue {
-        let old_value = if let Some(data) = self.0.get(key) {
+        let old_value = if let Some(data) = self.buckets.get(key) {
             data.value
         } else {
             return value;
@@ -172,60 +308,117 @@ impl LeakyBucket {
         old_value + value
     }
 
+    /// Get bucket data by bucket name
+    pub fn get(&self, key: &BucketName) -> Option<&BucketData> {
+        self.buckets.get(key)
+    }
+
     /// Update fill with prepared value by bucket name
     pub fn fill(&mut self, key: &BucketName, value: BucketValue) {
-        let bucket_data = BucketData {
-            value,
-            last_update: BucketPriorityQueue::current_time(),
-        };
-        *self.0.entry(key.clone()).or_insert(bucket_data) = bucket_data.clone();
+        let now = self.clock.now();
+        let bucket_data = self
+            .buckets
+            .entry(key.clone())
+            .or_insert_with(|| BucketData::new(now));
+        bucket_data.value = value;
+        bucket_data.last_update = now.as_secs();
+        bucket_data.updated_at = now;
     }
 
-    /// Leaky bucket algorithm
-    pub fn leaky(&mut self, key: &BucketName, config: &BucketConfig) {
-        use std::cmp::max;
+    /// Bucket fill if value is added by bucket rate limiter,
+    /// bucket isn't changed
+    pub fn check(
+        &self,
+        key: &BucketName,
+        value: BucketValue,
+        config: &BucketConfig,
+    ) -> BucketValue {
+        match self.buckets.get(key) {
+            Some(bucket_data) => {
+                config
+                    .algorithm
+                    .limiter()
+                    .check(bucket_data, value, self.clock.now(), config)
+            }
+            None => value,
+        }
+    }
 
-        // Get bucket
-        let bucket = if let Some(bucket) = self.0.get(key) {
-            bucket
-        } else {
-            return;
-        };
-        // Calculate leak amount
-        let duration = max(86400 / config.leak_rate, 1);
-        let current_time = BucketPriorityQueue::current_time();
-        let leak_time_delta = current_time - bucket.last_update;
-        if leak_time_delta < duration {
-            // NOP
-            return;
+    /// Decay bucket and add value by bucket rate limiter
+    pub fn add(&mut self, key: &BucketName, value: BucketValue, config: &BucketConfig) {
+        let now = self.clock.now();
+        let limiter = config.algorithm.limiter();
+        let bucket_data = self
+            .buckets
+            .entry(key.clone())
+            .or_insert_with(|| BucketData::new(now));
+        limiter.decay(bucket_data, now, config);
+        limiter.fill(bucket_data, value, now, config);
+        bucket_data.last_update = now.as_secs();
+    }
+
+    /// Set bucket to the base size and forget offending messages,
+    /// used after overflow
+    pub fn reset(&mut self, key: &BucketName, config: &BucketConfig) {
+        let now = self.clock.now();
+        let bucket_data = self
+            .buckets
+            .entry(key.clone())
+            .or_insert_with(|| BucketData::new(now));
+        config
+            .algorithm
+            .limiter()
+            .set(bucket_data, config.base_size, now, config);
+        bucket_data.last_update = now.as_secs();
+        bucket_data.first_offence = None;
+        bucket_data.last_offence = None;
+        bucket_data.level = None;
+    }
+
+    /// Set bucket level, return previous level
+    pub fn set_level(&mut self, key: &BucketName, level: Option<Level>) -> Option<Level> {
+        self.buckets
+            .get_mut(key)
+            .and_then(|bucket_data| std::mem::replace(&mut bucket_data.level, level))
+    }
+
+    /// Register offending message timestamp (in ms) for bucket
+    pub fn offend(&mut self, key: &BucketName, timestamp: u64) {
+        if let Some(bucket_data) = self.buckets.get_mut(key) {
+            bucket_data.first_offence.get_or_insert(timestamp);
+            bucket_data.last_offence = Some(timestamp);
         }
-        let leak_amount = config.leak_rate * leak_time_delta / 86400;
-        // Fill value always >= 0
-        let value = if bucket.value > leak_amount {
-            bucket.value - leak_amount
-        } else {
-            0
-        };
+    }
 
-        // Decrease bucket value and set last update time
-        let data = BucketData {
-            value,
-            last_update: BucketPriorityQueue::current_time(),
-        };
-        *self.0.entry(key.clone()).or_insert(data) = data.clone();
+    /// Decay bucket by bucket rate limiter
+    pub fn leaky(&mut self, key: &BucketName, config: &BucketConfig) {
+        let now = self.clock.now();
+        if let Some(bucket_data) = self.buckets.get_mut(key) {
+            config.algorithm.limiter().decay(bucket_data, now, config);
+        }
+    }
+
+    /// Live buckets count
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// Is there no live buckets
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
     }
 
     /// Remove leaky bucket  
     pub fn remove(&mut self, key: &BucketName) {
-        self.0.remove(key);
+        self.buckets.remove(key);
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::clock::MockClock;
     use ethereum_types::H160;
-    use std::thread::sleep;
 
     #[test]
     fn test_fill() {
@@ -260,26 +453,28 @@ mod tests {
             value: BucketNameValue::Address(addr),
             error: BucketErrorKind::Reverts,
         };
-        let mut lb = LeakyBucket::default();
+        let clock = MockClock::default();
+        let mut lb = LeakyBucket::with_clock(clock.shared());
         lb.fill(&bucket1, 10);
 
         let mut config = BucketConfig {
             base_size: 1,
             leak_rate: 100000,
-            overflow_size: 1,
+            overflow_size: Some(1),
             retention: Duration::from_secs(100),
+            ..BucketConfig::default()
         };
 
         let res = lb.get_fill(&bucket1, 0);
         assert_eq!(res, 10);
 
-        sleep(Duration::from_secs(1));
+        clock.advance(Duration::from_secs(1));
         lb.leaky(&bucket1, &config);
         let res = lb.get_fill(&bucket1, 0);
         assert_eq!(res, 9);
 
         // Leaky for x2 leaky rate
-        sleep(Duration::from_secs(2));
+        clock.advance(Duration::from_secs(2));
         lb.leaky(&bucket1, &config);
         let res = lb.get_fill(&bucket1, 0);
         assert_eq!(res, 7);
@@ -291,7 +486,7 @@ mod tests {
 
         // Leaky rate for NOP
         config.leak_rate = 10000;
-        sleep(Duration::from_secs(1));
+        clock.advance(Duration::from_secs(1));
         lb.leaky(&bucket1, &config);
         let res = lb.get_fill(&bucket1, 0);
         assert_eq!(res, 7);
@@ -299,7 +494,7 @@ mod tests {
         // Negative fill for leak: 1 - 2 result should be 0
         config.leak_rate = 100000;
         lb.fill(&bucket1, 1);
-        sleep(Duration::from_secs(2));
+        clock.advance(Duration::from_secs(2));
         lb.leaky(&bucket1, &config);
         let res = lb.get_fill(&bucket1, 0);
         assert_eq!(res, 0);
@@ -313,6 +508,16 @@ mod tests {
         lb.leaky(&bucket2, &config);
         let res = lb.get_fill(&bucket1, 0);
         assert_eq!(res, 0);
+
+        // Leak rate per day
+        config.leak_rate = 2;
+        lb.fill(&bucket1, 5);
+        clock.advance(Duration::from_secs(12 * 3600 - 1));
+        lb.leaky(&bucket1, &config);
+        assert_eq!(lb.get_fill(&bucket1, 0), 5);
+        clock.advance(Duration::from_secs(86400 + 1));
+        lb.leaky(&bucket1, &config);
+        assert_eq!(lb.get_fill(&bucket1, 0), 2);
     }
 
     #[test]
@@ -325,7 +530,8 @@ mod tests {
             value: BucketNameValue::Address(addr),
             error: BucketErrorKind::Reverts,
         };
-        let mut pq = BucketPriorityQueue::default();
+        let clock = MockClock::default();
+        let mut pq = BucketPriorityQueue::with_clock(clock.shared());
         pq.push(bucket1.clone());
 
         // Chekc is it exists in priority queue
@@ -343,7 +549,7 @@ mod tests {
         assert_eq!(&bucket1, bucket);
 
         // Success retention
-        sleep(Duration::from_secs(2));
+        clock.advance(Duration::from_secs(2));
         let buckets = pq.retention_free(1);
         assert_eq!(buckets.len(), 1);
         assert_eq!(buckets[0], bucket1);
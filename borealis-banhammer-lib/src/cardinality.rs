@@ -0,0 +1,161 @@
+//! # Cardinality
+//!
+//! Distinct sender addresses per identity over sliding window.
+//! Exact set of addresses with last seen time is kept,
+//! addresses out of window are dropped on every update.
+//!
+//! Rule fires when identity (client IP or token) sends
+//! transactions from more than `max_addresses` addresses
+//! within `window`.
+use crate::buckets::{BucketIdentity, BucketNameValue};
+use crate::de::{deserialize_duration, serialize_duration};
+use crate::limits::evict_least_recent;
+use ethereum_types::Address;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+
+/// Distinct addresses rule config
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct CardinalityConfig {
+    /// Max distinct addresses in window
+    pub max_addresses: u64,
+    /// Sliding window in seconds
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub window: Duration,
+}
+
+/// Addresses seen for identity value
+#[derive(Debug, Clone, Default)]
+pub struct AddressSet {
+    /// Address => last seen UNIX time in sec
+    addresses: HashMap<Address, u64>,
+    /// Relayer timestamp (in ms) of first message since set is created
+    pub first_seen: Option<u64>,
+    /// Relayer timestamp (in ms) of last message
+    pub last_seen: Option<u64>,
+}
+
+impl AddressSet {
+    /// Distinct addresses in window
+    pub fn len(&self) -> u64 {
+        self.addresses.len() as u64
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.addresses.is_empty()
+    }
+
+    /// Drop addresses out of window
+    fn expire(&mut self, now: u64, window: u64) {
+        self.addresses
+            .retain(|_, last_seen| *last_seen + window > now);
+        if self.addresses.is_empty() {
+            self.first_seen = None;
+            self.last_seen = None;
+        }
+    }
+}
+
+/// Distinct addresses by identity value
+#[derive(Default)]
+pub struct Cardinality(HashMap<BucketNameValue, AddressSet>);
+
+impl Cardinality {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add sender address for identity value.
+    /// Return: addresses, if max distinct addresses is exceeded.
+    /// Addresses of the value are reset in this case.
+    pub fn count(
+        &mut self,
+        value: &BucketNameValue,
+        address: Address,
+        now: u64,
+        timestamp: u64,
+        config: &CardinalityConfig,
+    ) -> Option<AddressSet> {
+        let set = self.0.entry(value.clone()).or_default();
+        set.expire(now, config.window.as_secs());
+        set.addresses.insert(address, now);
+        set.first_seen.get_or_insert(timestamp);
+        set.last_seen = Some(timestamp);
+
+        if set.len() > config.max_addresses {
+            return self.0.remove(value);
+        }
+        None
+    }
+
+    /// Get addresses for identity value
+    pub fn get(&self, value: &BucketNameValue) -> Option<&AddressSet> {
+        self.0.get(value)
+    }
+
+    /// Remove sets without addresses in window
+    pub fn retention_free(&mut self, now: u64, config: &CardinalityConfig) {
+        let window = config.window.as_secs();
+        self.0.retain(|_, set| {
+            set.expire(now, window);
+            !set.is_empty()
+        });
+    }
+
+    /// Remove least recently updated sets of identity kind over `max`.
+    /// Return: removed sets count
+    pub fn limit(&mut self, kind: &BucketIdentity, max: usize) -> usize {
+        evict_least_recent(
+            &mut self.0,
+            max,
+            |value, _| &value.identity() == kind,
+            |set| set.addresses.values().max().copied(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_cardinality() {
+        let value = BucketNameValue::IP(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        let config = CardinalityConfig {
+            max_addresses: 3,
+            window: Duration::from_secs(100),
+        };
+        let mut cardinality = Cardinality::default();
+
+        // Repeated addresses are counted once
+        for i in 1..=3 {
+            for _ in 0..5 {
+                let address = Address::from_low_u64_be(i);
+                assert!(cardinality
+                    .count(&value, address, i * 10, i, &config)
+                    .is_none());
+            }
+        }
+        assert_eq!(cardinality.get(&value).unwrap().len(), 3);
+
+        // First address is out of window
+        assert!(cardinality
+            .count(&value, Address::from_low_u64_be(4), 110, 4, &config)
+            .is_none());
+        let set = cardinality
+            .count(&value, Address::from_low_u64_be(5), 110, 5, &config)
+            .unwrap();
+        assert_eq!(set.len(), 4);
+        assert_eq!(set.first_seen, Some(1));
+        assert_eq!(set.last_seen, Some(5));
+        assert!(cardinality.get(&value).is_none());
+
+        cardinality.count(&value, Address::from_low_u64_be(6), 120, 6, &config);
+        cardinality.retention_free(220, &config);
+        assert!(cardinality.get(&value).is_none());
+    }
+}
@@ -0,0 +1,268 @@
+//! # Clock
+//!
+//! Time source for buckets, retention and bans.
+//! System clock is used by default, mock clock is
+//! advanced manually for tests and simulations.
+//!
+//! Event clock follows relayer messages timestamps, so
+//! lagging consumers and replays see traffic at the
+//! rate the relayer saw it. Event time is the watermark,
+//! the latest observed message timestamp. Message older
+//! than watermark by more than `tolerance` is late and
+//! dropped, out-of-order message within tolerance is
+//! processed at watermark. Without messages for
+//! `idle_timeout`, event time follows wall clock from
+//! watermark, so bans expire on idle stream. Event time
+//! never goes backwards, watermark is moved to the idle
+//! time when the stream resumes.
+//!
+//! Clock view shares time of other clock read-only, it's
+//! used by shards following coordinator's event time.
+use crate::de::{deserialize_duration, serialize_duration};
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+/// Time source, time is UNIX time
+pub trait Clock: Send + Sync {
+    /// Current time since UNIX epoch
+    fn now(&self) -> Duration;
+
+    /// Current UNIX time in sec
+    fn now_secs(&self) -> u64 {
+        self.now().as_secs()
+    }
+
+    /// Observe relayer message timestamp, wall clocks ignore it.
+    /// Return: false if message is late
+    fn observe(&self, _event_time: Duration) -> bool {
+        true
+    }
+}
+
+/// Clock shared by Banhammer components
+pub type SharedClock = Arc<dyn Clock>;
+
+/// Wall-clock time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl SystemClock {
+    pub fn shared() -> SharedClock {
+        Arc::new(SystemClock)
+    }
+}
+
+impl Clock for SystemClock {
+    /// Get current time without panic
+    fn now(&self) -> Duration {
+        match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(elapsed) => elapsed,
+            Err(e) => {
+                tracing::error!("{e:?}");
+                Duration::ZERO
+            }
+        }
+    }
+}
+
+/// Manually advanced clock with ms precision,
+/// clones share the same time
+#[derive(Debug, Clone, Default)]
+pub struct MockClock(Arc<AtomicU64>);
+
+impl MockClock {
+    pub fn new(now: Duration) -> Self {
+        Self(Arc::new(AtomicU64::new(now.as_millis() as u64)))
+    }
+
+    /// Set current time
+    pub fn set(&self, now: Duration) {
+        self.0.store(now.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    /// Move current time forward
+    pub fn advance(&self, duration: Duration) {
+        self.0
+            .fetch_add(duration.as_millis() as u64, Ordering::SeqCst);
+    }
+
+    pub fn shared(&self) -> SharedClock {
+        Arc::new(self.clone())
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Duration {
+        Duration::from_millis(self.0.load(Ordering::SeqCst))
+    }
+}
+
+/// Read-only view of shared clock,
+/// observed messages don't move it
+pub struct ClockView(SharedClock);
+
+impl ClockView {
+    pub fn shared(clock: SharedClock) -> SharedClock {
+        Arc::new(Self(clock))
+    }
+}
+
+impl Clock for ClockView {
+    fn now(&self) -> Duration {
+        self.0.now()
+    }
+}
+
+/// Event time config
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct EventTimeConfig {
+    /// Max out-of-order message lateness to watermark,
+    /// in seconds, fractions of second are allowed
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub tolerance: Duration,
+    /// Wall time without messages after which event time
+    /// follows wall clock, in seconds
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub idle_timeout: Duration,
+}
+
+/// Watermark state
+#[derive(Debug, Default)]
+struct Watermark {
+    /// The latest observed message timestamp
+    event_time: Option<Duration>,
+    /// Wall time when watermark moved
+    observed_at: Duration,
+}
+
+/// Relayer messages event time
+pub struct EventClock {
+    watermark: Mutex<Watermark>,
+    wall: SharedClock,
+    config: EventTimeConfig,
+}
+
+impl EventClock {
+    pub fn new(config: EventTimeConfig) -> Self {
+        Self::with_clock(SystemClock::shared(), config)
+    }
+
+    /// Event clock with custom wall clock for idle stream
+    pub fn with_clock(wall: SharedClock, config: EventTimeConfig) -> Self {
+        Self {
+            watermark: Mutex::new(Watermark::default()),
+            wall,
+            config,
+        }
+    }
+
+    pub fn shared(wall: SharedClock, config: EventTimeConfig) -> SharedClock {
+        Arc::new(Self::with_clock(wall, config))
+    }
+
+    /// The latest observed message timestamp
+    pub fn watermark(&self) -> Option<Duration> {
+        self.lock().event_time
+    }
+
+    /// Event time of watermark, None before the first message
+    fn event_now(&self, watermark: &Watermark) -> Option<Duration> {
+        let event_time = watermark.event_time?;
+        let idle = self.wall.now().saturating_sub(watermark.observed_at);
+        Some(event_time + idle.saturating_sub(self.config.idle_timeout))
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Watermark> {
+        // Watermark is consistent even if holder panicked
+        self.watermark
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Clock for EventClock {
+    /// Watermark, wall clock before the first message
+    fn now(&self) -> Duration {
+        let watermark = self.lock();
+        self.event_now(&watermark)
+            .unwrap_or_else(|| self.wall.now())
+    }
+
+    /// The first message sets watermark, nothing is
+    /// stamped with wall time before it
+    fn observe(&self, event_time: Duration) -> bool {
+        let mut watermark = self.lock();
+        let now = self.event_now(&watermark);
+        if matches!(now, Some(now) if event_time + self.config.tolerance < now) {
+            return false;
+        }
+        // Watermark keeps idle time, event time never goes backwards
+        watermark.event_time = Some(now.map_or(event_time, |now| now.max(event_time)));
+        watermark.observed_at = self.wall.now();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_clock() {
+        let secs = Duration::from_secs;
+        let wall = MockClock::new(secs(1000));
+        let clock = EventClock::with_clock(
+            wall.shared(),
+            EventTimeConfig {
+                tolerance: secs(5),
+                idle_timeout: secs(60),
+            },
+        );
+        // Wall clock before the first message
+        assert_eq!(clock.now(), secs(1000));
+
+        // Replayed messages move event time
+        assert!(clock.observe(secs(100)));
+        assert!(clock.observe(secs(110)));
+        assert_eq!(clock.now(), secs(110));
+        assert_eq!(clock.watermark(), Some(secs(110)));
+
+        // Out-of-order message within tolerance isn't late
+        assert!(clock.observe(secs(105)));
+        assert!(!clock.observe(secs(104)));
+        assert_eq!(clock.now(), secs(110));
+
+        // Idle stream follows wall clock after idle timeout
+        wall.advance(secs(60));
+        assert_eq!(clock.now(), secs(110));
+        wall.advance(secs(30));
+        assert_eq!(clock.now(), secs(140));
+
+        // Resumed stream doesn't move event time backwards
+        assert!(!clock.observe(secs(120)));
+        assert!(clock.observe(secs(136)));
+        assert_eq!(clock.now(), secs(140));
+        assert_eq!(clock.watermark(), Some(secs(140)));
+        wall.advance(secs(30));
+        assert_eq!(clock.now(), secs(140));
+        assert!(clock.observe(secs(150)));
+        assert_eq!(clock.now(), secs(150));
+
+        // View shares time, but doesn't observe messages
+        let view = ClockView::shared(Arc::new(clock));
+        assert!(view.observe(secs(200)));
+        assert_eq!(view.now(), secs(150));
+    }
+}
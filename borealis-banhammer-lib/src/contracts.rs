@@ -0,0 +1,104 @@
+//! # Hot contracts
+//!
+//! Buckets keyed on transaction target contract detect
+//! a contract hammered by many senders. Contract isn't
+//! banned on overflow, it becomes "hot" for a while and
+//! thresholds of senders calling it are divided by
+//! `threshold_divisor`.
+use crate::de::{deserialize_duration, serialize_duration};
+use ethereum_types::Address;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+
+/// Hot contracts config
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct HotContractConfig {
+    /// Senders thresholds divisor for hot contract calls
+    pub threshold_divisor: u64,
+    /// How long contract stays hot, in seconds
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub duration: Duration,
+}
+
+impl Default for HotContractConfig {
+    fn default() -> Self {
+        Self {
+            threshold_divisor: 2,
+            duration: Duration::from_secs(3600),
+        }
+    }
+}
+
+/// Hot contracts with expiry UNIX time in sec
+#[derive(Default)]
+pub struct HotContracts(HashMap<Address, u64>);
+
+impl HotContracts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mark contract hot and return its expiry time,
+    /// already hot contract is extended
+    pub fn heat(&mut self, contract: Address, now: u64, config: &HotContractConfig) -> u64 {
+        let expires_at = now.saturating_add(config.duration.as_secs());
+        let hot_until = self.0.entry(contract).or_insert(expires_at);
+        *hot_until = (*hot_until).max(expires_at);
+        *hot_until
+    }
+
+    /// Mark contract hot until expiry time,
+    /// already hot contract is extended
+    pub fn extend(&mut self, contract: Address, hot_until: u64) {
+        let expires_at = self.0.entry(contract).or_insert(hot_until);
+        *expires_at = (*expires_at).max(hot_until);
+    }
+
+    /// Is contract hot now
+    pub fn is_hot(&self, contract: &Address, now: u64) -> bool {
+        matches!(self.0.get(contract), Some(expires_at) if *expires_at > now)
+    }
+
+    /// Hot contracts count
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Forget cooled down contracts
+    pub fn expire(&mut self, now: u64) {
+        self.0.retain(|_, expires_at| *expires_at > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hot_contracts() {
+        let contract = Address::from_low_u64_be(1);
+        let config = HotContractConfig {
+            threshold_divisor: 2,
+            duration: Duration::from_secs(100),
+        };
+        let mut hot_contracts = HotContracts::default();
+        assert!(!hot_contracts.is_hot(&contract, 0));
+
+        assert_eq!(hot_contracts.heat(contract, 0, &config), 100);
+        assert!(hot_contracts.is_hot(&contract, 99));
+        assert!(!hot_contracts.is_hot(&contract, 100));
+        assert_eq!(hot_contracts.heat(contract, 50, &config), 150);
+
+        hot_contracts.expire(149);
+        assert_eq!(hot_contracts.len(), 1);
+        hot_contracts.expire(150);
+        assert!(hot_contracts.is_empty());
+    }
+}
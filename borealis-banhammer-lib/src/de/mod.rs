@@ -1,6 +1,6 @@
 use serde::{
     de::{self, Error, Visitor},
-    Deserializer,
+    Deserializer, Serializer,
 };
 
 use std::{
@@ -12,6 +12,19 @@ mod relayer;
 
 pub use relayer::{Params, RelayerMessage, Timestamp, Token, Transaction, TransactionError, Url};
 
+/// Serialize duration as seconds, symmetric to `deserialize_duration`.
+/// Fractional seconds are serialized as float
+pub fn serialize_duration<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    if duration.subsec_nanos() == 0 {
+        serializer.serialize_u64(duration.as_secs())
+    } else {
+        serializer.serialize_f64(duration.as_secs_f64())
+    }
+}
+
 pub fn deserialize_duration<'de, D>(deserializer: D) -> Result<Duration, D::Error>
 where
     D: Deserializer<'de>,
@@ -73,6 +86,19 @@ where
         {
             self.visit_u64(duration as u64)
         }
+
+        fn visit_f64<E>(self, duration: f64) -> Result<Self::Value, E>
+        where
+            E: Error,
+        {
+            // `Duration::from_secs_f64` panics on durations over u64 seconds
+            // (`Duration::try_from_secs_f64` needs newer toolchain than pinned)
+            if duration.is_finite() && duration >= 0.0 && duration < u64::MAX as f64 {
+                Ok(Duration::from_secs_f64(duration))
+            } else {
+                Err(Error::custom(format!("invalid duration: {}", duration)))
+            }
+        }
     }
 
     deserializer.deserialize_u64(DurationVisitor)
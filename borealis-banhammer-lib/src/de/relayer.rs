@@ -12,9 +12,25 @@ use std::{
 
 const RELAYER_ERR_PATTERN: &str = "httpsgithub.comaurora-is-nearaurora-relayerissues";
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Timestamp(Duration);
 
+impl Timestamp {
+    pub fn new(timestamp: Duration) -> Self {
+        Self(timestamp)
+    }
+
+    /// Timestamp in milliseconds
+    pub fn as_millis(&self) -> u64 {
+        self.0.as_millis() as u64
+    }
+
+    /// Timestamp since UNIX epoch
+    pub fn as_duration(&self) -> Duration {
+        self.0
+    }
+}
+
 impl<'de> Deserialize<'de> for Timestamp {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -26,11 +42,11 @@ impl<'de> Deserialize<'de> for Timestamp {
             type Value = Timestamp;
 
             fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
-                f.write_str("timestamp as u64")
+                f.write_str("timestamp in nanoseconds as u64")
             }
 
             fn visit_u64<E>(self, timestamp: u64) -> Result<Self::Value, E> {
-                let duration = Duration::from_millis(timestamp);
+                let duration = Duration::from_nanos(timestamp);
                 Ok(Timestamp(duration))
             }
         }
@@ -39,7 +55,7 @@ impl<'de> Deserialize<'de> for Timestamp {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct Transaction(Vec<u8>);
 
 impl<'de> Deserialize<'de> for Transaction {
@@ -94,7 +110,7 @@ impl fmt::Display for Transaction {
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 // TODO: Deserialize into actual result.
 pub struct EvmResult(Vec<u8>);
 
@@ -122,7 +138,7 @@ impl fmt::Display for EvmResult {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum SignatureVersion {
     Legacy,
     Eip2930,
@@ -219,7 +235,7 @@ where
     deserializer.deserialize_str(AddressVisitor)
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Params {
     pub from: Address,
     #[serde(rename = "sigver")]
@@ -238,9 +254,15 @@ pub struct Params {
     pub tx: Transaction,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Status(StatusCode);
 
+impl Status {
+    pub fn as_u16(&self) -> u16 {
+        self.0.as_u16()
+    }
+}
+
 impl<'de> Deserialize<'de> for Status {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -317,7 +339,7 @@ impl<'de> Deserialize<'de> for Status {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Url(Uri);
 
 impl<'de> Deserialize<'de> for Url {
@@ -431,9 +453,24 @@ where
     deserializer.deserialize_str(TransactionErrorVisitor)
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Token(String);
 
+impl Token {
+    pub fn new(token: &str) -> Self {
+        Self(token.to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Token is 43 or 44 characters long
+    pub fn is_valid(&self) -> bool {
+        self.0.len() == 44 || self.0.len() == 43
+    }
+}
+
 fn deserialize_token<'de, D>(deserializer: D) -> Result<Option<Token>, D::Error>
 where
     D: Deserializer<'de>,
@@ -451,12 +488,9 @@ where
         where
             E: de::Error,
         {
+            // Malformed token is kept, it gets unknown token tier
             if token.is_empty() {
                 Ok(None)
-            } else if token.len() != 44 && token.len() != 43 {
-                Err(de::Error::custom(
-                    "token length needs to be 44 characters long",
-                ))
             } else {
                 Ok(Some(Token(token.to_string())))
             }
@@ -466,7 +500,7 @@ where
     deserializer.deserialize_str(TokenVisitor)
 }
 
-#[derive(Debug, PartialEq, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct RelayerMessage {
     pub host: Url,
     pub timestamp: Timestamp,
@@ -483,6 +517,37 @@ pub struct RelayerMessage {
     pub params: Params,
 }
 
+#[cfg(test)]
+impl RelayerMessage {
+    /// Successful `eth_sendrawtransaction` message shared by tests,
+    /// tests set the fields they check
+    pub(crate) fn test_message() -> Self {
+        Self {
+            host: Url("westcoast004.relayers.aurora.dev".parse::<Uri>().unwrap()),
+            timestamp: Timestamp(Duration::from_nanos(1644082737464356000)),
+            status: Status(StatusCode::OK),
+            client: "197.251.253.48".parse().unwrap(),
+            response_time: 8.747,
+            error: None,
+            token: None,
+            method: "eth_sendrawtransaction".to_string(),
+            params: Params {
+                from: Address::from_slice(
+                    &hex::decode("b845796ae42f5061c65717e3e29ff33495b1652d").unwrap(),
+                ),
+                signature_version: SignatureVersion::Eip1559,
+                evm_result: None,
+                near_gas: 0,
+                to: None,
+                eth_gas: 6721975,
+                eth_nonce: 10,
+                eth_value: "0".to_string(),
+                tx: Transaction(vec![0]),
+            },
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::{
@@ -529,7 +594,7 @@ mod tests {
                 .unwrap();
         let expected = RelayerMessage {
             host: Url("westcoast004.relayers.aurora.dev".parse::<Uri>().unwrap()),
-            timestamp: Timestamp(Duration::from_millis(1644082737464356000)),
+            timestamp: Timestamp(Duration::from_nanos(1644082737464356000)),
             status: Status(StatusCode::OK),
             client: "197.251.253.48".parse().unwrap(),
             response_time: 8.747,
@@ -550,5 +615,6 @@ mod tests {
             },
         };
         assert_eq!(header, expected);
+        assert_eq!(header.timestamp.as_millis(), 1644082737464);
     }
 }
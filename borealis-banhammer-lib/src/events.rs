@@ -0,0 +1,153 @@
+//! # Events
+//!
+//! Events produced by Banhammer and published for other
+//! security/policy services.
+use crate::buckets::{
+    BucketConfig, BucketErrorKind, BucketIdentity, BucketName, BucketNameValue, BucketValue,
+};
+use crate::de::{RelayerMessage, Token};
+use crate::levels::Level;
+use ethereum_types::Address;
+use serde::Serialize;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Ban event - bucket overflow with the evidence of the decision,
+/// or bucket reached warn or throttle level
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct BanEvent {
+    pub identity: BucketIdentity,
+    pub value: BucketNameValue,
+    pub error: BucketErrorKind,
+    /// Bucket fill at overflow,
+    /// failed transactions percent for `FailureRatio`,
+    /// distinct addresses for `DistinctAddresses`
+    pub fill: BucketValue,
+    /// Overflow threshold,
+    /// max failed transactions percent for `FailureRatio`,
+    /// max distinct addresses for `DistinctAddresses`
+    pub threshold: BucketValue,
+    /// Bucket config used for decision, if it's bucket overflow
+    pub bucket: Option<BucketConfig>,
+    /// Relayer timestamp (in ms) of first offending message
+    pub first_offence: Option<u64>,
+    /// Relayer timestamp (in ms) of last offending message
+    pub last_offence: Option<u64>,
+    /// Ban or level UNIX time in sec
+    pub banned_at: u64,
+    /// Suggested ban duration in sec, 0 if value isn't banned
+    pub duration: u64,
+    /// Response level, only `Ban` level bans value
+    pub level: Level,
+    /// Suggested requests per second for `Throttle` level
+    pub requests_per_second: Option<f64>,
+    /// Banhammer instance which produced the event
+    pub instance_id: String,
+    /// Incident shared by ban events of one relayer message
+    pub incident_id: String,
+}
+
+impl BanEvent {
+    /// Bucket which has been overflowed
+    pub fn bucket_name(&self) -> BucketName {
+        BucketName::new(
+            self.identity.clone(),
+            self.value.clone(),
+            self.error.clone(),
+        )
+    }
+}
+
+/// Correlate ban events of one relayer message into single incident,
+/// simultaneous overflows of IP, address and token share incident ID
+pub fn correlate(ban_events: &mut [BanEvent], input: &RelayerMessage) {
+    let instance_id = match ban_events.first() {
+        Some(ban_event) => ban_event.instance_id.clone(),
+        None => return,
+    };
+    let mut hasher = DefaultHasher::new();
+    input.timestamp.as_duration().hash(&mut hasher);
+    input.client.hash(&mut hasher);
+    input.token.as_ref().map(Token::as_str).hash(&mut hasher);
+    input.params.from.hash(&mut hasher);
+    input.params.eth_nonce.hash(&mut hasher);
+    let incident_id = format!("{}-{:016x}", instance_id, hasher.finish());
+    for ban_event in ban_events.iter_mut() {
+        ban_event.incident_id = incident_id.clone();
+    }
+}
+
+/// Unban reason
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum UnbanReason {
+    /// Ban duration elapsed
+    Expired,
+    /// Ban lifted manually
+    Lifted,
+}
+
+/// Unban event - active ban is over
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UnbanEvent {
+    pub identity: BucketIdentity,
+    pub value: BucketNameValue,
+    /// Error kind of the last ban
+    pub error: BucketErrorKind,
+    pub reason: UnbanReason,
+    /// Ban UNIX time in sec
+    pub banned_at: u64,
+    /// Unban UNIX time in sec
+    pub unbanned_at: u64,
+    /// Offences count within memory window
+    pub offences: u32,
+    /// Banhammer instance which produced the event
+    pub instance_id: String,
+}
+
+/// Hot contract alert - target contract bucket overflow.
+/// Contract isn't banned, its senders get stricter thresholds
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct HotContractEvent {
+    pub contract: Address,
+    pub error: BucketErrorKind,
+    /// Bucket fill at overflow
+    pub fill: BucketValue,
+    /// Overflow threshold
+    pub threshold: BucketValue,
+    /// Bucket config used for decision
+    pub bucket: Option<BucketConfig>,
+    /// Relayer timestamp (in ms) of first offending message
+    pub first_offence: Option<u64>,
+    /// Relayer timestamp (in ms) of last offending message
+    pub last_offence: Option<u64>,
+    /// Detection UNIX time in sec
+    pub detected_at: u64,
+    /// Hot contract expiry UNIX time in sec
+    pub hot_until: u64,
+    /// Banhammer instance which produced the event
+    pub instance_id: String,
+}
+
+/// Banhammer event published to the bus
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event")]
+pub enum Event {
+    Ban(BanEvent),
+    /// Bucket reached warn or throttle level, value isn't banned
+    Level(BanEvent),
+    Unban(UnbanEvent),
+    HotContract(HotContractEvent),
+    /// Ban of candidate config evaluated in shadow mode,
+    /// nobody is banned actually
+    WouldBan(BanEvent),
+}
+
+impl From<BanEvent> for Event {
+    /// Ban or level event by ban event level
+    fn from(ban_event: BanEvent) -> Self {
+        match ban_event.level {
+            Level::Ban => Event::Ban(ban_event),
+            Level::Warn | Level::Throttle => Event::Level(ban_event),
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! # Exemptions
+//!
+//! Allowlist for own infrastructure, partners and load tests.
+//! Entry matches IP/CIDR range, address or token. Matched
+//! identity value is skipped, or its bucket thresholds are
+//! multiplied if entry has a multiplier. IP entry also applies
+//! to subnet buckets for messages of the exempted IPs.
+use crate::buckets::BucketNameValue;
+use crate::de::Token;
+use crate::subnet::Cidr;
+use ethereum_types::Address;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::net::IpAddr;
+
+/// Exemption entry, exactly one of `ip`, `address` or `token`
+/// is expected, checked on config load
+#[derive(Debug, Serialize, Clone, PartialEq)]
+pub struct Exemption {
+    pub ip: Option<Cidr>,
+    pub address: Option<Address>,
+    pub token: Option<Token>,
+    /// Bucket thresholds multiplier, full exemption if not set.
+    /// Multiplier 0 is rejected, zero threshold bans at once
+    pub multiplier: Option<u64>,
+}
+
+/// Exemption entry as it's written in config
+#[derive(Deserialize)]
+struct RawExemption {
+    #[serde(default)]
+    ip: Option<Cidr>,
+    #[serde(default)]
+    address: Option<Address>,
+    #[serde(default)]
+    token: Option<Token>,
+    #[serde(default)]
+    multiplier: Option<u64>,
+}
+
+impl<'de> Deserialize<'de> for Exemption {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let RawExemption {
+            ip,
+            address,
+            token,
+            multiplier,
+        } = RawExemption::deserialize(deserializer)?;
+        let identities = [ip.is_some(), address.is_some(), token.is_some()]
+            .iter()
+            .filter(|is_set| **is_set)
+            .count();
+        if identities != 1 {
+            return Err(serde::de::Error::custom(
+                "exemption expects exactly one of ip, address or token",
+            ));
+        }
+        if multiplier == Some(0) {
+            return Err(serde::de::Error::custom(
+                "exemption multiplier must be positive",
+            ));
+        }
+        Ok(Self {
+            ip,
+            address,
+            token,
+            multiplier,
+        })
+    }
+}
+
+impl Exemption {
+    fn matches(&self, value: &BucketNameValue, client: Option<&IpAddr>) -> bool {
+        match value {
+            BucketNameValue::IP(ip) => matches!(self.ip, Some(cidr) if cidr.contains(ip)),
+            // Subnet is exempted by range covering it, or by range
+            // covering message client IP of the subnet
+            BucketNameValue::Subnet(subnet) => match self.ip {
+                Some(cidr) => {
+                    cidr.contains_subnet(subnet)
+                        || matches!(client, Some(ip) if subnet.contains(ip) && cidr.contains(ip))
+                }
+                None => false,
+            },
+            // Composite value is exempted by any part
+            BucketNameValue::Composite(values) => {
+                values.iter().any(|value| self.matches(value, client))
+            }
+            BucketNameValue::Address(address) | BucketNameValue::Contract(address) => {
+                self.address.as_ref() == Some(address)
+            }
+            BucketNameValue::Token(token) => self.token.as_ref() == Some(token),
+        }
+    }
+}
+
+/// Exemption applied to identity value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exempt {
+    /// Buckets aren't processed
+    Full,
+    /// Bucket thresholds are multiplied
+    Multiplier(u64),
+}
+
+/// Exemptions list
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+#[serde(transparent)]
+pub struct Exemptions(Vec<Exemption>);
+
+impl Exemptions {
+    pub fn new(exemptions: Vec<Exemption>) -> Self {
+        Self(exemptions)
+    }
+
+    /// Exemption for identity value. Full exemption wins,
+    /// the biggest multiplier is used otherwise.
+    pub fn get(&self, value: &BucketNameValue) -> Option<Exempt> {
+        self.find(value, None)
+    }
+
+    /// Exemption for identity value of message from client IP.
+    /// Exempted client IP doesn't fill its subnet buckets,
+    /// other IPs of the subnet still fill them.
+    pub fn get_message(&self, value: &BucketNameValue, client: &IpAddr) -> Option<Exempt> {
+        self.find(value, Some(client))
+    }
+
+    fn find(&self, value: &BucketNameValue, client: Option<&IpAddr>) -> Option<Exempt> {
+        let mut exempt = None;
+        for exemption in self
+            .0
+            .iter()
+            .filter(|exemption| exemption.matches(value, client))
+        {
+            match exemption.multiplier {
+                None => return Some(Exempt::Full),
+                Some(multiplier) => {
+                    let max = match exempt {
+                        Some(Exempt::Multiplier(current)) => multiplier.max(current),
+                        _ => multiplier,
+                    };
+                    exempt = Some(Exempt::Multiplier(max));
+                }
+            }
+        }
+        exempt
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exemptions() {
+        #[derive(Deserialize)]
+        struct Config {
+            exemptions: Exemptions,
+        }
+        let Config { exemptions } = toml::from_str(
+            r#"
+exemptions = [
+    { ip = "10.0.0.0/8" },
+    { ip = "2001:db8::/32", multiplier = 5 },
+    { ip = "192.168.1.1", multiplier = 2 },
+    { ip = "192.168.0.0/16", multiplier = 10 },
+    { ip = "172.16.0.1", multiplier = 2 },
+    { address = "0xb845796ae42f5061c65717e3e29ff33495b1652d", multiplier = 3 },
+    { token = "load-test" },
+]
+"#,
+        )
+        .unwrap();
+        let ip = |ip: &str| BucketNameValue::IP(ip.parse().unwrap());
+
+        assert_eq!(exemptions.get(&ip("10.1.2.3")), Some(Exempt::Full));
+        assert_eq!(exemptions.get(&ip("11.1.2.3")), None);
+        assert_eq!(
+            exemptions.get(&ip("2001:db8::1")),
+            Some(Exempt::Multiplier(5))
+        );
+        assert_eq!(
+            exemptions.get(&ip("192.168.1.1")),
+            Some(Exempt::Multiplier(10))
+        );
+        assert_eq!(
+            exemptions.get(&BucketNameValue::Address(
+                "0xb845796ae42f5061c65717e3e29ff33495b1652d"
+                    .parse()
+                    .unwrap()
+            )),
+            Some(Exempt::Multiplier(3))
+        );
+        assert_eq!(
+            exemptions.get(&BucketNameValue::Token(Token::new("load-test"))),
+            Some(Exempt::Full)
+        );
+
+        // Subnet is exempted for messages of exempted IP only
+        let subnet = BucketNameValue::Subnet("172.16.0.0/24".parse().unwrap());
+        let client = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert_eq!(exemptions.get(&subnet), None);
+        assert_eq!(
+            exemptions.get_message(&subnet, &client("172.16.0.1")),
+            Some(Exempt::Multiplier(2))
+        );
+        assert_eq!(
+            exemptions.get_message(&subnet, &client("::ffff:172.16.0.1")),
+            Some(Exempt::Multiplier(2))
+        );
+        assert_eq!(exemptions.get_message(&subnet, &client("172.16.0.2")), None);
+        assert_eq!(
+            exemptions.get_message(
+                &BucketNameValue::Subnet("10.1.2.0/24".parse().unwrap()),
+                &client("10.1.2.3")
+            ),
+            Some(Exempt::Full)
+        );
+
+        // Entry expects one identity and positive multiplier
+        for invalid in [
+            r#"exemptions = [{ multiplier = 2 }]"#,
+            r#"exemptions = [{ ip = "10.0.0.0/8", token = "load-test" }]"#,
+            r#"exemptions = [{ ip = "10.0.0.0/8", multiplier = 0 }]"#,
+        ] {
+            assert!(toml::from_str::<Config>(invalid).is_err());
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! # Levels
+//!
+//! Graduated response to bucket fill. Bucket with `levels` config
+//! warns and throttles before overflow, fill is compared with
+//! bucket threshold in percent:
+//! - `Warn`: fill reached `warn_percent`
+//! - `Throttle`: fill reached `throttle_percent`, relayer should slow
+//!   client down to suggested requests per second
+//! - `Ban`: bucket overflow
+//!
+//! Event is produced once bucket reaches higher level,
+//! level is lowered as bucket leaks.
+use crate::buckets::{BucketConfig, BucketValue};
+use serde::{Deserialize, Serialize};
+
+/// Response level of bucket fill
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Level {
+    Warn,
+    Throttle,
+    Ban,
+}
+
+/// Bucket levels config, fill percent of overflow threshold
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+pub struct LevelConfig {
+    #[serde(default)]
+    pub warn_percent: Option<u64>,
+    #[serde(default)]
+    pub throttle_percent: Option<u64>,
+}
+
+impl LevelConfig {
+    /// Level of bucket fill below overflow threshold
+    pub fn level(&self, fill: BucketValue, threshold: BucketValue) -> Option<Level> {
+        let percent = fill.saturating_mul(100) / threshold.max(1);
+        if matches!(self.throttle_percent, Some(throttle) if percent >= throttle) {
+            Some(Level::Throttle)
+        } else if matches!(self.warn_percent, Some(warn) if percent >= warn) {
+            Some(Level::Warn)
+        } else {
+            None
+        }
+    }
+}
+
+/// Suggested requests per second keeping bucket from overflow:
+/// bucket leak rate per second divided by fill of one request
+pub fn requests_per_second(config: &BucketConfig, fill: BucketValue) -> f64 {
+    let period = config.period.as_secs_f64();
+    if period <= 0.0 {
+        return 0.0;
+    }
+    config.leak_rate as f64 / period / fill.max(1) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_levels() {
+        let levels = LevelConfig {
+            warn_percent: Some(60),
+            throttle_percent: Some(80),
+        };
+        assert_eq!(levels.level(59, 100), None);
+        assert_eq!(levels.level(6, 10), Some(Level::Warn));
+        assert_eq!(levels.level(80, 100), Some(Level::Throttle));
+        assert_eq!(LevelConfig::default().level(99, 100), None);
+        assert!(Level::Warn < Level::Throttle && Level::Throttle < Level::Ban);
+
+        // 100 units per 10 sec, 5 units per request
+        let config = BucketConfig {
+            leak_rate: 100,
+            period: Duration::from_secs(10),
+            ..BucketConfig::default()
+        };
+        assert_eq!(requests_per_second(&config, 5), 2.0);
+    }
+}
@@ -1,4 +1,21 @@
 pub mod banhammer;
+pub mod bans;
 pub mod buckets;
+pub mod cardinality;
+pub mod clock;
+pub mod contracts;
 pub mod de;
+pub mod events;
+pub mod exemptions;
+pub mod levels;
+pub mod limiters;
+pub mod limits;
+pub mod methods;
+pub mod nonces;
+pub mod ratio;
+pub mod reputation;
+pub mod rules;
+pub mod shards;
 pub mod stats;
+pub mod subnet;
+pub mod tiers;
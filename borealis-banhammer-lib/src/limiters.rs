@@ -0,0 +1,348 @@
+//! # Rate limiters
+//!
+//! Algorithms limiting bucket fill, selected by bucket config
+//! `algorithm`. Rate is `leak_rate` units per `period`, time is
+//! measured with sub-second precision:
+//! - `LeakyBucket`: leaks whole units, time of partial unit is
+//!   carried over. Default, compatible with per-day `leak_rate`
+//! - `TokenBucket`: taken tokens are returned continuously,
+//!   time of partial token is carried over
+//! - `SlidingWindow`: units counted in `period` windows, previous
+//!   window count is weighted by its overlap with sliding window
+//! - `Gcra`: generic cell rate algorithm, bucket keeps theoretical
+//!   arrival time, fill is time left to it in emission intervals
+use crate::buckets::{BucketConfig, BucketData, BucketValue};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Rate limiter algorithm, `LeakyBucket` by default
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    LeakyBucket,
+    TokenBucket,
+    SlidingWindow,
+    Gcra,
+}
+
+impl Algorithm {
+    /// Rate limiter of the algorithm
+    pub fn limiter(&self) -> &'static dyn RateLimiter {
+        match self {
+            Algorithm::LeakyBucket => &LeakyBucketLimiter,
+            Algorithm::TokenBucket => &TokenBucketLimiter,
+            Algorithm::SlidingWindow => &SlidingWindowLimiter,
+            Algorithm::Gcra => &GcraLimiter,
+        }
+    }
+}
+
+/// Rate limiter over bucket data
+pub trait RateLimiter: Send + Sync {
+    /// Decay bucket fill to current time
+    fn decay(&self, bucket: &mut BucketData, now: Duration, config: &BucketConfig);
+
+    /// Add value to decayed bucket
+    fn fill(
+        &self,
+        bucket: &mut BucketData,
+        value: BucketValue,
+        now: Duration,
+        config: &BucketConfig,
+    );
+
+    /// Set bucket fill, used after overflow
+    fn set(
+        &self,
+        bucket: &mut BucketData,
+        value: BucketValue,
+        now: Duration,
+        _config: &BucketConfig,
+    ) {
+        bucket.value = value;
+        bucket.previous = 0;
+        bucket.updated_at = now;
+    }
+
+    /// Bucket fill if value is added, bucket isn't changed
+    fn check(
+        &self,
+        bucket: &BucketData,
+        value: BucketValue,
+        now: Duration,
+        config: &BucketConfig,
+    ) -> BucketValue {
+        let mut bucket = bucket.clone();
+        self.decay(&mut bucket, now, config);
+        self.fill(&mut bucket, value, now, config);
+        bucket.value
+    }
+}
+
+fn period_nanos(config: &BucketConfig) -> u128 {
+    config.period.as_nanos().max(1)
+}
+
+/// Time of one unit leak in ns, None if bucket doesn't leak
+fn interval_nanos(config: &BucketConfig) -> Option<u128> {
+    match config.leak_rate {
+        0 => None,
+        leak_rate => Some((period_nanos(config) / leak_rate as u128).max(1)),
+    }
+}
+
+/// Units leaked within time
+fn leaked(elapsed: Duration, config: &BucketConfig) -> u64 {
+    let leaked = elapsed.as_nanos() * config.leak_rate as u128 / period_nanos(config);
+    u64::try_from(leaked).unwrap_or(u64::MAX)
+}
+
+fn nanos(nanos: u128) -> Duration {
+    Duration::from_nanos(u64::try_from(nanos).unwrap_or(u64::MAX))
+}
+
+pub struct LeakyBucketLimiter;
+
+impl RateLimiter for LeakyBucketLimiter {
+    fn decay(&self, bucket: &mut BucketData, now: Duration, config: &BucketConfig) {
+        let interval = match interval_nanos(config) {
+            Some(interval) => interval,
+            None => return,
+        };
+        let leak_amount = leaked(now.saturating_sub(bucket.updated_at), config);
+        if leak_amount == 0 {
+            // NOP
+            return;
+        }
+        if leak_amount >= bucket.value {
+            bucket.value = 0;
+            bucket.updated_at = now;
+        } else {
+            // Time of partial unit is kept, so fills more often
+            // than leak interval don't stop the leak
+            bucket.value -= leak_amount;
+            bucket.updated_at += nanos(leak_amount as u128 * interval);
+        }
+    }
+
+    fn fill(&self, bucket: &mut BucketData, value: BucketValue, now: Duration, _: &BucketConfig) {
+        // Leak timer starts with the first unit
+        if bucket.value == 0 {
+            bucket.updated_at = now;
+        }
+        bucket.value = bucket.value.saturating_add(value);
+    }
+}
+
+pub struct TokenBucketLimiter;
+
+impl RateLimiter for TokenBucketLimiter {
+    fn decay(&self, bucket: &mut BucketData, now: Duration, config: &BucketConfig) {
+        let interval = match interval_nanos(config) {
+            Some(interval) => interval,
+            None => return,
+        };
+        let returned = leaked(now.saturating_sub(bucket.updated_at), config);
+        if returned >= bucket.value {
+            bucket.value = 0;
+            bucket.updated_at = now;
+        } else {
+            // Time of partial token is kept
+            bucket.value -= returned;
+            bucket.updated_at += nanos(returned as u128 * interval);
+        }
+    }
+
+    fn fill(&self, bucket: &mut BucketData, value: BucketValue, now: Duration, _: &BucketConfig) {
+        if bucket.value == 0 {
+            bucket.updated_at = now;
+        }
+        bucket.value = bucket.value.saturating_add(value);
+    }
+}
+
+pub struct SlidingWindowLimiter;
+
+impl RateLimiter for SlidingWindowLimiter {
+    fn decay(&self, bucket: &mut BucketData, now: Duration, config: &BucketConfig) {
+        let period = period_nanos(config);
+        let windows = now.saturating_sub(bucket.updated_at).as_nanos() / period;
+        match windows {
+            0 => return,
+            1 => bucket.previous = bucket.value,
+            _ => bucket.previous = 0,
+        }
+        bucket.value = 0;
+        bucket.updated_at += nanos(windows * period);
+    }
+
+    fn fill(&self, bucket: &mut BucketData, value: BucketValue, _: Duration, _: &BucketConfig) {
+        bucket.value = bucket.value.saturating_add(value);
+    }
+
+    fn check(
+        &self,
+        bucket: &BucketData,
+        value: BucketValue,
+        now: Duration,
+        config: &BucketConfig,
+    ) -> BucketValue {
+        let mut bucket = bucket.clone();
+        self.decay(&mut bucket, now, config);
+        let period = period_nanos(config);
+        let overlap = period.saturating_sub(now.saturating_sub(bucket.updated_at).as_nanos());
+        let previous =
+            u64::try_from(bucket.previous as u128 * overlap / period).unwrap_or(u64::MAX);
+        bucket.value.saturating_add(value).saturating_add(previous)
+    }
+}
+
+pub struct GcraLimiter;
+
+impl GcraLimiter {
+    /// Emission intervals left to theoretical arrival time
+    fn value(tat: Duration, now: Duration, interval: u128) -> BucketValue {
+        let left = tat.saturating_sub(now).as_nanos();
+        let value = left / interval + (left % interval).min(1);
+        u64::try_from(value).unwrap_or(u64::MAX)
+    }
+}
+
+impl RateLimiter for GcraLimiter {
+    fn decay(&self, bucket: &mut BucketData, now: Duration, config: &BucketConfig) {
+        if let Some(interval) = interval_nanos(config) {
+            bucket.value = Self::value(bucket.updated_at, now, interval);
+        }
+    }
+
+    fn fill(
+        &self,
+        bucket: &mut BucketData,
+        value: BucketValue,
+        now: Duration,
+        config: &BucketConfig,
+    ) {
+        let interval = match interval_nanos(config) {
+            Some(interval) => interval,
+            None => {
+                bucket.value = bucket.value.saturating_add(value);
+                return;
+            }
+        };
+        let tat = bucket.updated_at.max(now);
+        bucket.updated_at = tat.saturating_add(nanos(value as u128 * interval));
+        bucket.value = Self::value(bucket.updated_at, now, interval);
+    }
+
+    fn set(
+        &self,
+        bucket: &mut BucketData,
+        value: BucketValue,
+        now: Duration,
+        config: &BucketConfig,
+    ) {
+        bucket.value = value;
+        bucket.previous = 0;
+        bucket.updated_at = match interval_nanos(config) {
+            Some(interval) => now.saturating_add(nanos(value as u128 * interval)),
+            None => now,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limiters() {
+        let ms = Duration::from_millis;
+        // 10 units per second
+        let config = |algorithm| BucketConfig {
+            base_size: 0,
+            leak_rate: 10,
+            overflow_size: Some(5),
+            retention: Duration::from_secs(60),
+            algorithm,
+            period: Duration::from_secs(1),
+            levels: None,
+        };
+
+        // Partial leak time is carried over fills
+        let config_leaky = config(Algorithm::LeakyBucket);
+        let leaky = Algorithm::LeakyBucket.limiter();
+        let mut bucket = BucketData::new(ms(0));
+        leaky.fill(&mut bucket, 5, ms(0), &config_leaky);
+        assert_eq!(leaky.check(&bucket, 0, ms(150), &config_leaky), 4);
+        leaky.decay(&mut bucket, ms(150), &config_leaky);
+        leaky.fill(&mut bucket, 1, ms(150), &config_leaky);
+        assert_eq!(leaky.check(&bucket, 0, ms(240), &config_leaky), 4);
+
+        // Partial token time is carried over
+        let config_token = config(Algorithm::TokenBucket);
+        let token = Algorithm::TokenBucket.limiter();
+        let mut bucket = BucketData::new(ms(0));
+        token.fill(&mut bucket, 5, ms(0), &config_token);
+        token.decay(&mut bucket, ms(150), &config_token);
+        token.fill(&mut bucket, 1, ms(150), &config_token);
+        assert_eq!(bucket.value, 5);
+        assert_eq!(token.check(&bucket, 0, ms(200), &config_token), 4);
+        assert_eq!(token.check(&bucket, 2, ms(1000), &config_token), 2);
+
+        // Previous window is weighted by overlap
+        let config_window = config(Algorithm::SlidingWindow);
+        let window = Algorithm::SlidingWindow.limiter();
+        let mut bucket = BucketData::new(ms(0));
+        window.fill(&mut bucket, 10, ms(0), &config_window);
+        assert_eq!(window.check(&bucket, 0, ms(999), &config_window), 10);
+        assert_eq!(window.check(&bucket, 1, ms(1250), &config_window), 8);
+        assert_eq!(window.check(&bucket, 1, ms(2000), &config_window), 1);
+
+        // Fill is emission intervals left to theoretical arrival time
+        let config_gcra = config(Algorithm::Gcra);
+        let gcra = Algorithm::Gcra.limiter();
+        let mut bucket = BucketData::new(ms(0));
+        gcra.fill(&mut bucket, 5, ms(0), &config_gcra);
+        assert_eq!(bucket.updated_at, ms(500));
+        assert_eq!(gcra.check(&bucket, 0, ms(250), &config_gcra), 3);
+        assert_eq!(gcra.check(&bucket, 1, ms(1000), &config_gcra), 1);
+        gcra.set(&mut bucket, 2, ms(1000), &config_gcra);
+        assert_eq!(gcra.check(&bucket, 0, ms(1100), &config_gcra), 1);
+    }
+
+    #[test]
+    fn test_leaky_frequent_fills() {
+        // 24 units per day, filled every 59 minutes for a day
+        let config = BucketConfig {
+            base_size: 0,
+            leak_rate: 24,
+            overflow_size: Some(5),
+            retention: Duration::from_secs(86400),
+            algorithm: Algorithm::LeakyBucket,
+            period: Duration::from_secs(86400),
+            levels: None,
+        };
+        let leaky = Algorithm::LeakyBucket.limiter();
+        let mut bucket = BucketData::new(Duration::ZERO);
+        for fill in 0..24 {
+            let now = Duration::from_secs(fill * 59 * 60);
+            leaky.decay(&mut bucket, now, &config);
+            leaky.fill(&mut bucket, 1, now, &config);
+            // Leak keeps up with fills more often than leak interval
+            assert!(bucket.value <= 2, "fill {fill}: {}", bucket.value);
+        }
+    }
+
+    #[test]
+    fn test_period_config() {
+        let bucket = |period| {
+            toml::from_str::<BucketConfig>(&format!(
+                "base_size = 0\nleak_rate = 1\nretention = 60\nperiod = {period}"
+            ))
+        };
+        assert_eq!(bucket("0.5").unwrap().period, Duration::from_millis(500));
+        // Too big period is an error instead of panic
+        assert!(bucket("1e30").is_err());
+        assert!(bucket("-1.0").is_err());
+        assert!(bucket("nan").is_err());
+    }
+}
@@ -0,0 +1,60 @@
+//! # Limits
+//!
+//! Caps of identity values kept by Banhammer components
+//! besides leaky buckets: reputation scores, failure ratio
+//! counters, distinct addresses, last nonces and bans history.
+//! Live buckets cap of identity kind is applied to them on
+//! tick, least recently updated values are evicted over the cap.
+use std::{cmp::Ordering, collections::HashMap, hash::Hash};
+
+/// Remove least recently updated entries matching `filter`
+/// over `max` entries, `updated_at` is entry update time.
+/// Return: removed entries count
+pub fn evict_least_recent<K, V, T>(
+    map: &mut HashMap<K, V>,
+    max: usize,
+    filter: impl Fn(&K, &V) -> bool,
+    updated_at: impl Fn(&V) -> T,
+) -> usize
+where
+    K: Eq + Hash + Clone,
+    T: PartialOrd,
+{
+    let matched = map.iter().filter(|(key, value)| filter(key, value)).count();
+    if matched <= max {
+        return 0;
+    }
+    let count = matched - max;
+    let mut entries: Vec<(T, K)> = map
+        .iter()
+        .filter(|(key, value)| filter(key, value))
+        .map(|(key, value)| (updated_at(value), key.clone()))
+        .collect();
+    entries.select_nth_unstable_by(count - 1, |a, b| {
+        a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal)
+    });
+    for (_, key) in entries.iter().take(count) {
+        map.remove(key);
+    }
+    count
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evict_least_recent() {
+        let mut map: HashMap<u64, u64> = (0..10).map(|key| (key, 100 - key)).collect();
+        // Odd keys only, the least recent are the greatest keys
+        let evicted = evict_least_recent(&mut map, 2, |key, _| key % 2 == 1, |value| *value);
+        assert_eq!(evicted, 3);
+        assert_eq!(map.len(), 7);
+        assert!(map.contains_key(&1) && map.contains_key(&3));
+        assert!(!map.contains_key(&5) && !map.contains_key(&9));
+        assert_eq!(
+            evict_least_recent(&mut map, 2, |key, _| key % 2 == 1, |value| *value),
+            0
+        );
+    }
+}
@@ -0,0 +1,121 @@
+//! # Methods
+//!
+//! Per RPC method policies. Relayer interceptor forwards
+//! more than `eth_sendRawTransaction`, policy sets which
+//! methods are evaluated, bucket configs overriding
+//! `leaky_buckets` and fill weight for method's messages.
+//!
+//! Method is matched case-insensitive. All methods are
+//! evaluated with common buckets if no policy is set.
+use crate::banhammer::LeakyBucketConfig;
+use crate::buckets::{BucketConfig, BucketErrorKind, BucketIdentity};
+use serde::{Deserialize, Serialize};
+
+/// RPC method policy
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MethodPolicy {
+    /// RPC method, case-insensitive
+    pub method: String,
+    /// Bucket fill multiplier for method's messages
+    #[serde(default = "default_weight")]
+    pub weight: u64,
+    /// Bucket configs overriding common ones for method's messages
+    #[serde(default)]
+    pub leaky_buckets: Vec<LeakyBucketConfig>,
+}
+
+fn default_weight() -> u64 {
+    1
+}
+
+impl MethodPolicy {
+    /// Bucket config of the policy
+    pub fn get_bucket_config(
+        &self,
+        kind: &BucketIdentity,
+        err: &BucketErrorKind,
+    ) -> Option<BucketConfig> {
+        self.leaky_buckets
+            .iter()
+            .find(|config| &config.identity == kind && &config.error_kind == err)
+            .map(|config| config.bucket)
+    }
+}
+
+/// RPC method policies
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+#[serde(transparent)]
+pub struct MethodPolicies(Vec<MethodPolicy>);
+
+impl MethodPolicies {
+    pub fn new(policies: Vec<MethodPolicy>) -> Self {
+        Self(policies)
+    }
+
+    /// Policy of RPC method
+    pub fn get(&self, method: &str) -> Option<&MethodPolicy> {
+        self.0
+            .iter()
+            .find(|policy| policy.method.eq_ignore_ascii_case(method))
+    }
+
+    /// Is RPC method evaluated: any method if no policy is set
+    pub fn evaluates(&self, method: &str) -> bool {
+        self.0.is_empty() || self.get(method).is_some()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MethodPolicy> {
+        self.0.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_policies() {
+        #[derive(Deserialize)]
+        struct Config {
+            methods: MethodPolicies,
+        }
+        let Config { methods } = toml::from_str(
+            r#"
+[[methods]]
+method = "eth_sendRawTransaction"
+
+[[methods]]
+method = "eth_call"
+weight = 3
+[[methods.leaky_buckets]]
+identity = "IP"
+error_kind = "Reverts"
+bucket = { base_size = 0, leak_rate = 1, overflow_size = 100, retention = 60 }
+"#,
+        )
+        .unwrap();
+
+        assert!(methods.evaluates("eth_sendrawtransaction"));
+        assert!(methods.evaluates("ETH_CALL"));
+        assert!(!methods.evaluates("eth_getBalance"));
+        assert!(MethodPolicies::default().evaluates("eth_getBalance"));
+
+        let policy = methods.get("eth_call").unwrap();
+        assert_eq!(policy.weight, 3);
+        assert_eq!(
+            policy
+                .get_bucket_config(&BucketIdentity::IP, &BucketErrorKind::Reverts)
+                .and_then(|config| config.overflow_size),
+            Some(100)
+        );
+        assert!(methods
+            .get("eth_sendRawTransaction")
+            .unwrap()
+            .get_bucket_config(&BucketIdentity::IP, &BucketErrorKind::Reverts)
+            .is_none());
+    }
+}
@@ -0,0 +1,156 @@
+//! # Nonces
+//!
+//! Last successful nonce per address. Incorrect nonce
+//! failure is classified by gap to the expected nonce:
+//! - too low: replays or resubmits
+//! - too high: queue flooding
+//!
+//! Gap within `tolerance` (wallet fallen behind) is
+//! tolerated and doesn't fill buckets.
+use crate::de::{deserialize_duration, serialize_duration, RelayerMessage, TransactionError};
+use crate::limits::evict_least_recent;
+use ethereum_types::Address;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+
+/// Nonce tracking config
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct NonceConfig {
+    /// Max tolerated gap to expected nonce
+    pub tolerance: u64,
+    /// How long last nonce is kept, in seconds
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub retention: Duration,
+}
+
+/// Incorrect nonce class
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum NonceGap {
+    /// No successful nonce for address
+    Unknown,
+    /// Gap within tolerance
+    Tolerated,
+    /// Nonce below expected
+    TooLow,
+    /// Nonce above expected
+    TooHigh,
+}
+
+/// Last nonce data
+#[derive(Debug, Clone, Copy)]
+struct LastNonce {
+    nonce: u64,
+    /// Last update UNIX time in sec
+    updated_at: u64,
+}
+
+/// Last successful nonces by address
+#[derive(Default)]
+pub struct NonceTracker(HashMap<Address, LastNonce>);
+
+impl NonceTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register successful transaction nonce
+    pub fn success(&mut self, address: Address, nonce: u64, now: u64) {
+        let last = self.0.entry(address).or_insert(LastNonce {
+            nonce,
+            updated_at: now,
+        });
+        last.nonce = last.nonce.max(nonce);
+        last.updated_at = now;
+    }
+
+    /// Classify incorrect nonce of address
+    pub fn classify(&self, address: &Address, nonce: u64, config: &NonceConfig) -> NonceGap {
+        let expected = match self.0.get(address) {
+            Some(last) => last.nonce.saturating_add(1),
+            None => return NonceGap::Unknown,
+        };
+        if nonce.abs_diff(expected) <= config.tolerance {
+            NonceGap::Tolerated
+        } else if nonce < expected {
+            NonceGap::TooLow
+        } else {
+            NonceGap::TooHigh
+        }
+    }
+
+    /// Register successful nonce of sender address,
+    /// or classify incorrect nonce.
+    /// Return: incorrect nonce class
+    pub fn track(
+        &mut self,
+        input: &RelayerMessage,
+        config: Option<&NonceConfig>,
+        now: u64,
+    ) -> Option<NonceGap> {
+        let nonce = input.params.eth_nonce as u64;
+        match (&input.error, config) {
+            (Some(TransactionError::ErrIncorrectNonce), Some(config)) => {
+                Some(self.classify(&input.params.from, nonce, config))
+            }
+            (Some(TransactionError::ErrIncorrectNonce), None) => Some(NonceGap::Unknown),
+            (None, Some(_)) => {
+                self.success(input.params.from, nonce, now);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Last successful nonce of address
+    pub fn get(&self, address: &Address) -> Option<u64> {
+        self.0.get(address).map(|last| last.nonce)
+    }
+
+    /// Forget addresses not updated for retention time
+    pub fn retention_free(&mut self, now: u64, config: &NonceConfig) {
+        let retention = config.retention.as_secs();
+        self.0
+            .retain(|_, last| now.saturating_sub(last.updated_at) <= retention);
+    }
+
+    /// Forget least recently updated addresses over `max`.
+    /// Return: forgotten addresses count
+    pub fn limit(&mut self, max: usize) -> usize {
+        evict_least_recent(&mut self.0, max, |_, _| true, |last| last.updated_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nonce_gap() {
+        let address = Address::from_low_u64_be(1);
+        let config = NonceConfig {
+            tolerance: 1,
+            retention: Duration::from_secs(100),
+        };
+        let mut tracker = NonceTracker::default();
+        assert_eq!(tracker.classify(&address, 5, &config), NonceGap::Unknown);
+
+        tracker.success(address, 10, 0);
+        // Expected nonce is 11
+        assert_eq!(tracker.classify(&address, 10, &config), NonceGap::Tolerated);
+        assert_eq!(tracker.classify(&address, 12, &config), NonceGap::Tolerated);
+        assert_eq!(tracker.classify(&address, 3, &config), NonceGap::TooLow);
+        assert_eq!(tracker.classify(&address, 20, &config), NonceGap::TooHigh);
+
+        // Out of order success doesn't move nonce back
+        tracker.success(address, 8, 50);
+        assert_eq!(tracker.get(&address), Some(10));
+
+        tracker.retention_free(150, &config);
+        assert_eq!(tracker.get(&address), Some(10));
+        tracker.retention_free(151, &config);
+        assert_eq!(tracker.get(&address), None);
+    }
+}
@@ -0,0 +1,216 @@
+//! # Failure ratio
+//!
+//! Successful and failed transactions counters per identity
+//! over sliding window. Window is split into slots and
+//! expired slots are dropped on every update.
+//!
+//! Rule fires when failed transactions share exceeds
+//! `max_failure_percent` and window has at least
+//! `min_samples` transactions.
+use crate::buckets::{BucketIdentity, BucketNameValue};
+use crate::de::{deserialize_duration, serialize_duration};
+use crate::limits::evict_least_recent;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+/// Slots count for sliding window
+const WINDOW_SLOTS: u64 = 10;
+
+/// Failure ratio rule config
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FailureRatioConfig {
+    /// Max share of failed transactions in percents
+    pub max_failure_percent: u64,
+    /// Min transactions count in window to apply the rule
+    pub min_samples: u64,
+    /// Sliding window in seconds
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub window: Duration,
+}
+
+/// Window slot counters
+#[derive(Debug, Clone)]
+struct Slot {
+    start: u64,
+    successes: u64,
+    failures: u64,
+}
+
+/// Transactions counters for identity value
+#[derive(Debug, Clone, Default)]
+pub struct RatioCounters {
+    slots: VecDeque<Slot>,
+    /// Last update UNIX time in sec
+    updated_at: u64,
+    /// Relayer timestamp (in ms) of first failed transaction
+    pub first_failure: Option<u64>,
+    /// Relayer timestamp (in ms) of last failed transaction
+    pub last_failure: Option<u64>,
+}
+
+impl RatioCounters {
+    /// Successful transactions in window
+    pub fn successes(&self) -> u64 {
+        self.slots.iter().map(|slot| slot.successes).sum()
+    }
+
+    /// Failed transactions in window
+    pub fn failures(&self) -> u64 {
+        self.slots.iter().map(|slot| slot.failures).sum()
+    }
+
+    /// All transactions in window
+    pub fn total(&self) -> u64 {
+        self.successes() + self.failures()
+    }
+
+    /// Failed transactions share in percents
+    pub fn failure_percent(&self) -> u64 {
+        match self.total() {
+            0 => 0,
+            total => self.failures() * 100 / total,
+        }
+    }
+
+    /// Drop slots out of window
+    fn expire(&mut self, now: u64, window: u64) {
+        while let Some(slot) = self.slots.front() {
+            if slot.start + window <= now {
+                self.slots.pop_front();
+            } else {
+                break;
+            }
+        }
+        if self.slots.is_empty() {
+            self.first_failure = None;
+            self.last_failure = None;
+        }
+    }
+}
+
+/// Failure ratio counters by identity value
+#[derive(Default)]
+pub struct FailureRatio(HashMap<BucketNameValue, RatioCounters>);
+
+impl FailureRatio {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count transaction result for identity value.
+    /// Return: counters, if failure ratio is exceeded.
+    /// Counters of the value are reset in this case.
+    pub fn count(
+        &mut self,
+        value: &BucketNameValue,
+        failed: bool,
+        now: u64,
+        timestamp: u64,
+        config: &FailureRatioConfig,
+    ) -> Option<RatioCounters> {
+        let window = config.window.as_secs();
+        let slot_size = std::cmp::max(window / WINDOW_SLOTS, 1);
+        let slot_start = now - now % slot_size;
+
+        let counters = self.0.entry(value.clone()).or_default();
+        counters.expire(now, window);
+        counters.updated_at = now;
+        match counters.slots.back_mut() {
+            Some(slot) if slot.start == slot_start => (),
+            _ => counters.slots.push_back(Slot {
+                start: slot_start,
+                successes: 0,
+                failures: 0,
+            }),
+        }
+        let slot = counters.slots.back_mut()?;
+        if failed {
+            slot.failures += 1;
+            counters.first_failure.get_or_insert(timestamp);
+            counters.last_failure = Some(timestamp);
+        } else {
+            slot.successes += 1;
+        }
+
+        if counters.total() >= config.min_samples
+            && counters.failure_percent() > config.max_failure_percent
+        {
+            return self.0.remove(value);
+        }
+        None
+    }
+
+    /// Get counters for identity value
+    pub fn get(&self, value: &BucketNameValue) -> Option<&RatioCounters> {
+        self.0.get(value)
+    }
+
+    /// Remove counters without transactions in window
+    pub fn retention_free(&mut self, now: u64, config: &FailureRatioConfig) {
+        let window = config.window.as_secs();
+        self.0.retain(|_, counters| {
+            counters.expire(now, window);
+            !counters.slots.is_empty()
+        });
+    }
+
+    /// Remove least recently updated counters of identity kind over `max`.
+    /// Return: removed counters count
+    pub fn limit(&mut self, kind: &BucketIdentity, max: usize) -> usize {
+        evict_least_recent(
+            &mut self.0,
+            max,
+            |value, _| &value.identity() == kind,
+            |counters| counters.updated_at,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_failure_ratio() {
+        let value = BucketNameValue::IP(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        let config = FailureRatioConfig {
+            max_failure_percent: 20,
+            min_samples: 10,
+            window: Duration::from_secs(100),
+        };
+        let mut ratio = FailureRatio::default();
+
+        // Not enough samples
+        for _ in 0..5 {
+            assert!(ratio.count(&value, true, 0, 1, &config).is_none());
+        }
+        // 5 of 9 failed, still not enough samples
+        for _ in 0..4 {
+            assert!(ratio.count(&value, false, 0, 2, &config).is_none());
+        }
+        let counters = ratio.count(&value, false, 0, 3, &config).unwrap();
+        assert_eq!(counters.failures(), 5);
+        assert_eq!(counters.successes(), 5);
+        assert_eq!(counters.failure_percent(), 50);
+        assert_eq!(counters.first_failure, Some(1));
+        assert_eq!(counters.last_failure, Some(1));
+        assert!(ratio.get(&value).is_none());
+
+        // High volume with 20% failures
+        for i in 0..100 {
+            assert!(ratio.count(&value, i % 5 == 4, 10, 4, &config).is_none());
+        }
+        assert_eq!(ratio.get(&value).unwrap().failure_percent(), 20);
+
+        // Sliding window drops old transactions
+        ratio.retention_free(110, &config);
+        assert!(ratio.get(&value).is_none());
+    }
+}
@@ -0,0 +1,218 @@
+//! # Reputation
+//!
+//! Decaying reputation score per client IP, address and token.
+//! Every transaction adds weighted good or bad points, score
+//! decays exponentially to neutral 0 with `half_life`:
+//!
+//! score = score * 0.5 ^ (elapsed / half_life) + points
+//!
+//! Negative score is poor reputation. Score is available
+//! for rules as predicate input.
+use crate::buckets::{BucketIdentity, BucketNameValue};
+use crate::de::{deserialize_duration, serialize_duration, TransactionError};
+use crate::limits::evict_least_recent;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, time::Duration};
+
+/// Reputation config
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ReputationConfig {
+    /// Score half-life in seconds
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub half_life: Duration,
+    /// How long score is kept without updates, in seconds
+    #[serde(
+        deserialize_with = "deserialize_duration",
+        serialize_with = "serialize_duration"
+    )]
+    pub retention: Duration,
+    #[serde(default)]
+    pub weights: ReputationWeights,
+}
+
+/// Points of transaction events
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct ReputationWeights {
+    pub success: f64,
+    pub revert: f64,
+    pub incorrect_nonce: f64,
+    pub max_gas: f64,
+    pub invalid_ecdsa: f64,
+    /// Points per Tgas of NEAR gas used over per-transaction
+    /// estimate, usual transaction gets no gas points
+    pub near_tgas: f64,
+}
+
+impl Default for ReputationWeights {
+    fn default() -> Self {
+        Self {
+            success: 1.0,
+            revert: -5.0,
+            incorrect_nonce: -2.0,
+            max_gas: -5.0,
+            invalid_ecdsa: -10.0,
+            near_tgas: -0.01,
+        }
+    }
+}
+
+impl ReputationWeights {
+    /// Points of transaction, relayer issues aren't user's failures.
+    /// `near_gas_estimate` is NEAR gas of usual transaction
+    pub fn points(
+        &self,
+        maybe_error: Option<&TransactionError>,
+        near_gas: u64,
+        near_gas_estimate: u64,
+    ) -> f64 {
+        let points = match maybe_error {
+            None => self.success,
+            Some(TransactionError::Revert(_)) => self.revert,
+            Some(TransactionError::ErrIncorrectNonce) => self.incorrect_nonce,
+            Some(TransactionError::MaxGas) => self.max_gas,
+            Some(TransactionError::InvalidECDSA) => self.invalid_ecdsa,
+            Some(TransactionError::Relayer(_)) => return 0.0,
+        };
+        let excessive_gas = near_gas.saturating_sub(near_gas_estimate);
+        points + self.near_tgas * excessive_gas as f64 / 1e12
+    }
+}
+
+/// Score data
+#[derive(Debug, Clone, Copy)]
+struct Score {
+    value: f64,
+    /// Last update time in sec
+    updated_at: f64,
+}
+
+impl Score {
+    fn decayed(&self, now: f64, half_life: f64) -> f64 {
+        let elapsed = (now - self.updated_at).max(0.0);
+        if half_life <= 0.0 {
+            return 0.0;
+        }
+        self.value * 0.5_f64.powf(elapsed / half_life)
+    }
+}
+
+/// Reputation scores by identity value
+#[derive(Default)]
+pub struct Reputation(HashMap<BucketNameValue, Score>);
+
+impl Reputation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add points to decayed score of identity value.
+    /// Return: updated score
+    pub fn record(
+        &mut self,
+        value: &BucketNameValue,
+        points: f64,
+        now: Duration,
+        config: &ReputationConfig,
+    ) -> f64 {
+        let now = now.as_secs_f64();
+        let half_life = config.half_life.as_secs_f64();
+        let score = self.0.entry(value.clone()).or_insert(Score {
+            value: 0.0,
+            updated_at: now,
+        });
+        score.value = score.decayed(now, half_life) + points;
+        score.updated_at = now;
+        score.value
+    }
+
+    /// Decayed score of identity value
+    pub fn get(
+        &self,
+        value: &BucketNameValue,
+        now: Duration,
+        config: &ReputationConfig,
+    ) -> Option<f64> {
+        self.0
+            .get(value)
+            .map(|score| score.decayed(now.as_secs_f64(), config.half_life.as_secs_f64()))
+    }
+
+    /// Scored identity values count
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Forget scores not updated for retention time
+    pub fn retention_free(&mut self, now: Duration, config: &ReputationConfig) {
+        let now = now.as_secs_f64();
+        let retention = config.retention.as_secs_f64();
+        self.0
+            .retain(|_, score| now - score.updated_at <= retention);
+    }
+
+    /// Forget least recently updated scores of identity kind over `max`.
+    /// Return: forgotten scores count
+    pub fn limit(&mut self, kind: &BucketIdentity, max: usize) -> usize {
+        evict_least_recent(
+            &mut self.0,
+            max,
+            |value, _| &value.identity() == kind,
+            |score| score.updated_at,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    #[test]
+    fn test_reputation() {
+        let value = BucketNameValue::IP(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        let config = ReputationConfig {
+            half_life: Duration::from_secs(100),
+            retention: Duration::from_secs(1000),
+            weights: ReputationWeights::default(),
+        };
+        let secs = Duration::from_secs;
+        let mut reputation = Reputation::default();
+        assert!(reputation.get(&value, secs(0), &config).is_none());
+
+        let revert = TransactionError::Revert("revert".to_string());
+        let points = config.weights.points(Some(&revert), 0, 0);
+        assert_eq!(reputation.record(&value, points, secs(0), &config), -5.0);
+        assert_eq!(reputation.record(&value, points, secs(0), &config), -10.0);
+        // Half of score is left after half-life
+        assert_eq!(reputation.get(&value, secs(100), &config), Some(-5.0));
+        let points = config.weights.points(None, 0, 0);
+        assert_eq!(reputation.record(&value, points, secs(200), &config), -1.5);
+
+        reputation.retention_free(secs(1200), &config);
+        assert_eq!(reputation.len(), 1);
+        reputation.retention_free(secs(1201), &config);
+        assert!(reputation.is_empty());
+    }
+
+    #[test]
+    fn test_default_weights() {
+        let weights = ReputationWeights::default();
+        let estimate = 202651902028573;
+        // Usual successful transaction is good
+        assert_eq!(weights.points(None, estimate, estimate), 1.0);
+        assert_eq!(weights.points(None, 0, estimate), 1.0);
+        // 300 Tgas over estimate outweigh success
+        let points = weights.points(None, estimate + 300_000_000_000_000, estimate);
+        assert!((points + 2.0).abs() < 1e-9);
+        let revert = TransactionError::Revert("revert".to_string());
+        assert_eq!(weights.points(Some(&revert), estimate, estimate), -5.0);
+    }
+}
@@ -0,0 +1,441 @@
+//! # Rules
+//!
+//! Declarative rules mapping relayer messages to leaky buckets.
+//! Rule contains:
+//! - identities the rule applies to
+//! - predicate over relayer message fields
+//! - fill source and weight
+//! - target bucket error kind
+//!
+//! All predicate fields are optional, rule without predicate
+//! matches every transaction. Default rules reproduce
+//! built-in gas and error buckets.
+use crate::buckets::{BucketErrorKind, BucketIdentity};
+use crate::de::TransactionError;
+use crate::nonces::NonceGap;
+use ethereum_types::Address;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Transaction error variant to match
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorVariant {
+    /// Successful transaction
+    Success,
+    ErrIncorrectNonce,
+    MaxGas,
+    InvalidECDSA,
+    Revert,
+    Relayer,
+}
+
+impl ErrorVariant {
+    fn of(maybe_error: Option<&TransactionError>) -> Self {
+        match maybe_error {
+            None => ErrorVariant::Success,
+            Some(TransactionError::ErrIncorrectNonce) => ErrorVariant::ErrIncorrectNonce,
+            Some(TransactionError::MaxGas) => ErrorVariant::MaxGas,
+            Some(TransactionError::InvalidECDSA) => ErrorVariant::InvalidECDSA,
+            Some(TransactionError::Revert(_)) => ErrorVariant::Revert,
+            Some(TransactionError::Relayer(_)) => ErrorVariant::Relayer,
+        }
+    }
+}
+
+/// Regular expression, compiled on config load
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern)
+            .map(Pattern)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+/// Inclusive range, bounds are optional
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub struct Range {
+    #[serde(default)]
+    pub min: Option<u64>,
+    #[serde(default)]
+    pub max: Option<u64>,
+}
+
+impl Range {
+    fn contains(&self, value: u64) -> bool {
+        !matches!(self.min, Some(min) if value < min)
+            && !matches!(self.max, Some(max) if value > max)
+    }
+}
+
+/// Inclusive score range, bounds are optional
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+pub struct ScoreRange {
+    #[serde(default)]
+    pub min: Option<f64>,
+    #[serde(default)]
+    pub max: Option<f64>,
+}
+
+impl ScoreRange {
+    fn contains(&self, value: f64) -> bool {
+        !matches!(self.min, Some(min) if value < min)
+            && !matches!(self.max, Some(max) if value > max)
+    }
+}
+
+/// Relayer message fields used by rules
+#[derive(Debug, Clone, Copy)]
+pub struct RuleInput<'a> {
+    pub maybe_error: Option<&'a TransactionError>,
+    pub method: &'a str,
+    pub status: u16,
+    pub to: Option<Address>,
+    pub near_gas: u64,
+    pub eth_gas: u64,
+    /// Incorrect nonce class, set for incorrect nonce errors only
+    pub nonce_gap: Option<NonceGap>,
+    /// Reputation score of identity value, set if reputation is enabled
+    pub reputation: Option<f64>,
+}
+
+/// Rule predicate, all set fields must match
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct Predicate {
+    /// Any of transaction error variants
+    #[serde(default)]
+    pub error: Option<Vec<ErrorVariant>>,
+    /// Regular expression for revert message
+    #[serde(default)]
+    pub revert: Option<Pattern>,
+    /// Any of RPC methods, case-insensitive
+    #[serde(default)]
+    pub method: Option<Vec<String>>,
+    /// Any of HTTP statuses
+    #[serde(default)]
+    pub status: Option<Vec<u16>>,
+    /// Any of transaction target addresses
+    #[serde(default)]
+    pub to: Option<Vec<Address>>,
+    /// NEAR gas range
+    #[serde(default)]
+    pub near_gas: Option<Range>,
+    /// EVM gas range
+    #[serde(default)]
+    pub eth_gas: Option<Range>,
+    /// Any of incorrect nonce classes
+    #[serde(default)]
+    pub nonce: Option<Vec<NonceGap>>,
+    /// Reputation score range of identity value
+    #[serde(default)]
+    pub reputation: Option<ScoreRange>,
+}
+
+impl Predicate {
+    pub fn matches(&self, input: &RuleInput) -> bool {
+        if let Some(errors) = self.error.as_ref() {
+            if !errors.contains(&ErrorVariant::of(input.maybe_error)) {
+                return false;
+            }
+        }
+        if let Some(Pattern(regex)) = self.revert.as_ref() {
+            match input.maybe_error {
+                Some(TransactionError::Revert(message)) if regex.is_match(message) => (),
+                _ => return false,
+            }
+        }
+        if let Some(methods) = self.method.as_ref() {
+            if !methods
+                .iter()
+                .any(|method| method.eq_ignore_ascii_case(input.method))
+            {
+                return false;
+            }
+        }
+        if let Some(statuses) = self.status.as_ref() {
+            if !statuses.contains(&input.status) {
+                return false;
+            }
+        }
+        if let Some(addresses) = self.to.as_ref() {
+            match input.to {
+                Some(to) if addresses.contains(&to) => (),
+                _ => return false,
+            }
+        }
+        if let Some(range) = self.near_gas.as_ref() {
+            if !range.contains(input.near_gas) {
+                return false;
+            }
+        }
+        if let Some(range) = self.eth_gas.as_ref() {
+            if !range.contains(input.eth_gas) {
+                return false;
+            }
+        }
+        if let Some(gaps) = self.nonce.as_ref() {
+            match input.nonce_gap {
+                Some(gap) if gaps.contains(&gap) => (),
+                _ => return false,
+            }
+        }
+        if let Some(range) = self.reputation.as_ref() {
+            match input.reputation {
+                Some(score) if range.contains(score) => (),
+                _ => return false,
+            }
+        }
+        true
+    }
+}
+
+/// Bucket fill source
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum FillSource {
+    /// Fill by transactions count
+    Count,
+    /// Fill by NEAR gas used
+    NearGas,
+    /// Fill by EVM gas used
+    EvmGas,
+}
+
+/// Rule config
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Rule {
+    /// Rule name for logs
+    #[serde(default)]
+    pub name: String,
+    /// Identities the rule applies to
+    pub identities: Vec<BucketIdentity>,
+    #[serde(default)]
+    pub predicate: Predicate,
+    #[serde(default = "default_fill")]
+    pub fill: FillSource,
+    /// Fill multiplier
+    #[serde(default = "default_weight")]
+    pub weight: u64,
+    /// Target bucket error kind
+    pub error_kind: BucketErrorKind,
+}
+
+fn default_fill() -> FillSource {
+    FillSource::Count
+}
+
+fn default_weight() -> u64 {
+    1
+}
+
+impl Rule {
+    fn new(name: &str, fill: FillSource, error_kind: BucketErrorKind) -> Self {
+        Self {
+            name: name.to_string(),
+            identities: vec![
+                BucketIdentity::IP,
+                BucketIdentity::Address,
+                BucketIdentity::Token,
+                BucketIdentity::Subnet,
+                BucketIdentity::Contract,
+            ],
+            predicate: Predicate::default(),
+            fill,
+            weight: default_weight(),
+            error_kind,
+        }
+    }
+
+    fn with_errors(mut self, errors: Vec<ErrorVariant>) -> Self {
+        self.predicate.error = Some(errors);
+        self
+    }
+
+    fn with_nonce(mut self, gaps: Vec<NonceGap>) -> Self {
+        self.predicate.nonce = Some(gaps);
+        self
+    }
+
+    /// Rule applies to composite identity if it's listed
+    /// or rule applies to all its parts
+    fn applies_to(&self, identity: &BucketIdentity) -> bool {
+        if self.identities.contains(identity) {
+            return true;
+        }
+        match identity {
+            BucketIdentity::Composite(parts) => parts.iter().all(|part| self.applies_to(part)),
+            _ => false,
+        }
+    }
+
+    /// Bucket fill for identity, if rule is matched
+    pub fn apply(&self, identity: &BucketIdentity, input: &RuleInput) -> Option<u64> {
+        if !self.applies_to(identity) || !self.predicate.matches(input) {
+            return None;
+        }
+        let fill = match self.fill {
+            FillSource::Count => 1,
+            FillSource::NearGas => input.near_gas,
+            FillSource::EvmGas => input.eth_gas,
+        };
+        Some(fill.saturating_mul(self.weight))
+    }
+}
+
+/// Built-in rules: gas buckets are filled by every
+/// transaction, error buckets by failed transactions
+pub fn default_rules() -> Vec<Rule> {
+    vec![
+        Rule::new(
+            "near_gas",
+            FillSource::NearGas,
+            BucketErrorKind::UsedExcessiveGas,
+        ),
+        Rule::new(
+            "evm_gas",
+            FillSource::EvmGas,
+            BucketErrorKind::UsedExcessiveEvmGas,
+        ),
+        Rule::new(
+            "incorrect_nonce",
+            FillSource::Count,
+            BucketErrorKind::IncorrectNonce,
+        )
+        .with_errors(vec![ErrorVariant::ErrIncorrectNonce])
+        .with_nonce(vec![NonceGap::Unknown]),
+        Rule::new(
+            "invalid_ecdsa",
+            FillSource::Count,
+            BucketErrorKind::IncorrectNonce,
+        )
+        .with_errors(vec![ErrorVariant::InvalidECDSA]),
+        Rule::new(
+            "nonce_too_low",
+            FillSource::Count,
+            BucketErrorKind::NonceTooLow,
+        )
+        .with_nonce(vec![NonceGap::TooLow]),
+        Rule::new(
+            "nonce_too_high",
+            FillSource::Count,
+            BucketErrorKind::NonceTooHigh,
+        )
+        .with_nonce(vec![NonceGap::TooHigh]),
+        Rule::new("max_gas", FillSource::Count, BucketErrorKind::MaxGas)
+            .with_errors(vec![ErrorVariant::MaxGas]),
+        Rule::new("reverts", FillSource::Count, BucketErrorKind::Reverts)
+            .with_errors(vec![ErrorVariant::Revert]),
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_predicate() {
+        let rule: Rule = toml::from_str(
+            r#"
+name = "swap_spam"
+identities = ["IP", "Address"]
+weight = 5
+error_kind = { Custom = "swap_spam" }
+
+[predicate]
+error = ["Revert"]
+revert = "(?i)insufficient_output"
+method = ["eth_sendRawTransaction"]
+status = [200]
+to = ["0xb845796ae42f5061c65717e3e29ff33495b1652d"]
+eth_gas = { min = 100000 }
+reputation = { max = -10.0 }
+"#,
+        )
+        .unwrap();
+        let to: Address = "0xb845796ae42f5061c65717e3e29ff33495b1652d"
+            .parse()
+            .unwrap();
+        let revert = TransactionError::Revert("INSUFFICIENT_OUTPUT_AMOUNT".to_string());
+        let other_revert = TransactionError::Revert("EXPIRED".to_string());
+        let input = RuleInput {
+            maybe_error: Some(&revert),
+            method: "eth_sendrawtransaction",
+            status: 200,
+            to: Some(to),
+            near_gas: 0,
+            eth_gas: 200000,
+            nonce_gap: None,
+            reputation: Some(-20.0),
+        };
+        assert_eq!(rule.apply(&BucketIdentity::IP, &input), Some(5));
+        assert_eq!(rule.apply(&BucketIdentity::Token, &input), None);
+        assert_eq!(
+            rule.apply(
+                &BucketIdentity::Composite(vec![BucketIdentity::IP, BucketIdentity::Address]),
+                &input
+            ),
+            Some(5)
+        );
+        assert_eq!(
+            rule.apply(
+                &BucketIdentity::Composite(vec![BucketIdentity::Token, BucketIdentity::Address]),
+                &input
+            ),
+            None
+        );
+        assert_eq!(
+            rule.apply(
+                &BucketIdentity::IP,
+                &RuleInput {
+                    maybe_error: Some(&other_revert),
+                    ..input
+                }
+            ),
+            None
+        );
+        assert_eq!(
+            rule.apply(
+                &BucketIdentity::IP,
+                &RuleInput {
+                    eth_gas: 50000,
+                    ..input
+                }
+            ),
+            None
+        );
+        assert_eq!(
+            rule.apply(&BucketIdentity::IP, &RuleInput { to: None, ..input }),
+            None
+        );
+        assert_eq!(
+            rule.apply(
+                &BucketIdentity::IP,
+                &RuleInput {
+                    reputation: Some(-5.0),
+                    ..input
+                }
+            ),
+            None
+        );
+    }
+}
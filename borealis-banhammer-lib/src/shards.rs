@@ -0,0 +1,477 @@
+//! # Shards
+//!
+//! Sharded Banhammer for multi-core hosts. Buckets are
+//! partitioned by hash of identity value across shards,
+//! each shard is a `Banhammer` owned by its worker thread.
+//! Identity value is always processed by the same shard
+//! in messages order, so per-identity ordering is kept.
+//!
+//! Nonces and late messages are tracked by coordinator before
+//! messages are dispatched to shards, coordinator owns the only
+//! event clock and shards read its time. Hot contracts detected by a shard
+//! are shared with all shards after each batch of messages.
+//! Live buckets caps are split between shards.
+use crate::banhammer::{Banhammer, Config};
+use crate::buckets::{BucketIdentity, BucketNameValue};
+use crate::clock::{ClockView, EventClock, SharedClock, SystemClock};
+use crate::de::RelayerMessage;
+use crate::events::{correlate, BanEvent, HotContractEvent, UnbanEvent};
+use crate::nonces::{NonceGap, NonceTracker};
+use crate::stats::{Counter, Gauge, LiveMetrics, SharedMetrics};
+use crate::tiers::TokenRegistry;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
+
+/// Task executed by shard on its Banhammer
+type Task<R> = Box<dyn FnOnce(&mut Banhammer) -> R + Send>;
+
+/// Shard worker thread
+struct Worker {
+    tx: Option<mpsc::Sender<Task<()>>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    fn spawn(shard: usize, mut banhammer: Banhammer) -> Self {
+        let (tx, rx) = mpsc::channel::<Task<()>>();
+        let handle = thread::Builder::new()
+            .name(format!("banhammer-shard-{shard}"))
+            .spawn(move || {
+                for task in rx {
+                    task(&mut banhammer);
+                }
+            })
+            .expect("Failed to spawn Banhammer shard thread");
+        Self {
+            tx: Some(tx),
+            handle: Some(handle),
+        }
+    }
+
+    /// Queue task for shard thread, panics if the thread is stopped:
+    /// buckets of its identity values are lost
+    fn send(&self, task: Task<()>) {
+        let sent = self.tx.as_ref().map(|tx| tx.send(task).is_ok());
+        if sent != Some(true) {
+            panic!("Banhammer shard thread is stopped");
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        // Stop receiving tasks and wait for queued ones
+        self.tx.take();
+        if let Some(handle) = self.handle.take() {
+            if let Err(e) = handle.join() {
+                tracing::error!("Banhammer shard thread panicked: {e:?}");
+            }
+        }
+    }
+}
+
+/// Identity value of relayer message processed by shard
+struct Job {
+    /// Position of identity ban events in batch
+    position: usize,
+    input: Arc<RelayerMessage>,
+    nonce_gap: Option<NonceGap>,
+    identity: BucketIdentity,
+    value: BucketNameValue,
+}
+
+/// Shard config, live buckets caps are split between shards.
+/// Event time is observed by coordinator
+fn shard_config(config: &Config, shards: usize) -> Config {
+    let mut config = config.clone();
+    config.event_time = None;
+    for limit in config.bucket_limits.iter_mut() {
+        limit.max_buckets = limit.max_buckets / shards + (limit.max_buckets % shards).min(1);
+    }
+    config
+}
+
+/// Banhammer with buckets partitioned across worker threads
+pub struct ShardedBanhammer {
+    config: Config,
+    nonces: NonceTracker,
+    hot_contract_events: Vec<HotContractEvent>,
+    workers: Vec<Worker>,
+    clock: SharedClock,
+    metrics: SharedMetrics,
+}
+
+impl ShardedBanhammer {
+    pub fn new(config: Config, shards: usize) -> Self {
+        Self::with_clock(config, shards, SystemClock::shared())
+    }
+
+    /// Sharded Banhammer with custom time source, at least one shard
+    pub fn with_clock(config: Config, shards: usize, clock: SharedClock) -> Self {
+        let shards = shards.max(1);
+        let clock = match config.event_time {
+            Some(event_time) => EventClock::shared(clock, event_time),
+            None => clock,
+        };
+        let workers = (0..shards)
+            .map(|shard| {
+                let banhammer = Banhammer::with_clock(
+                    shard_config(&config, shards),
+                    ClockView::shared(clock.clone()),
+                );
+                Worker::spawn(shard, banhammer)
+            })
+            .collect();
+        Self {
+            config,
+            nonces: NonceTracker::default(),
+            hot_contract_events: vec![],
+            workers,
+            clock,
+            metrics: LiveMetrics::shared(),
+        }
+    }
+
+    /// Replace metrics sink of coordinator and all shards
+    pub fn set_metrics(&mut self, metrics: SharedMetrics) {
+        self.metrics = metrics.clone();
+        self.broadcast(move |banhammer| banhammer.set_metrics(metrics.clone()));
+    }
+
+    /// Shards count
+    pub fn shards(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Shard owning identity value buckets
+    fn shard(&self, value: &BucketNameValue) -> usize {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        (hasher.finish() % self.workers.len() as u64) as usize
+    }
+
+    /// Execute tasks on shards in parallel and wait for results,
+    /// panics if a shard thread is stopped instead of partial results.
+    /// Return: results in shards order
+    fn execute<R: Send + 'static>(&self, tasks: Vec<(usize, Task<R>)>) -> Vec<R> {
+        let expected = tasks.len();
+        let (tx, rx) = mpsc::channel();
+        for (shard, task) in tasks {
+            let tx = tx.clone();
+            self.workers[shard].send(Box::new(move |banhammer| {
+                // Coordinator waits for all results, it can't be gone
+                let _ = tx.send((shard, task(banhammer)));
+            }));
+        }
+        drop(tx);
+        let mut results: Vec<(usize, R)> = rx.iter().collect();
+        // Task is dropped without result if its shard thread panicked
+        if results.len() != expected {
+            panic!("Banhammer shard thread stopped while executing task");
+        }
+        results.sort_by_key(|(shard, _)| *shard);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+
+    /// Execute the same task on every shard
+    fn broadcast<R, F>(&self, task: F) -> Vec<R>
+    where
+        R: Send + 'static,
+        F: Fn(&mut Banhammer) -> R + Clone + Send + 'static,
+    {
+        let tasks = (0..self.workers.len())
+            .map(|shard| (shard, Box::new(task.clone()) as Task<R>))
+            .collect();
+        self.execute(tasks)
+    }
+
+    /// Execute task on shard owning identity value
+    fn execute_on<R, F>(&self, value: &BucketNameValue, task: F) -> Option<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Banhammer) -> R + Send + 'static,
+    {
+        let tasks = vec![(self.shard(value), Box::new(task) as Task<R>)];
+        self.execute(tasks).pop()
+    }
+
+    /// Lift active ban for identity value manually
+    pub fn unban(&mut self, value: &BucketNameValue) -> Option<UnbanEvent> {
+        let key = value.clone();
+        self.execute_on(value, move |banhammer| banhammer.unban(&key))
+            .flatten()
+    }
+
+    /// Replace current config of all shards, buckets state is kept
+    pub fn update_config(&mut self, config: Config) {
+        let shard_config = shard_config(&config, self.workers.len());
+        self.config = config;
+        self.broadcast(move |banhammer| banhammer.update_config(shard_config.clone()));
+    }
+
+    /// Replace token registry of all shards
+    pub fn update_token_registry(&mut self, tokens: TokenRegistry) {
+        self.broadcast(move |banhammer| banhammer.update_token_registry(tokens.clone()));
+    }
+
+    /// Current reputation score of identity value
+    pub fn reputation(&self, value: &BucketNameValue) -> Option<f64> {
+        let key = value.clone();
+        self.execute_on(value, move |banhammer| banhammer.reputation(&key))
+            .flatten()
+    }
+
+    /// Hot contract events produced since the last call
+    pub fn take_hot_contract_events(&mut self) -> Vec<HotContractEvent> {
+        std::mem::take(&mut self.hot_contract_events)
+    }
+
+    /// Share hot contracts detected by shards with all shards
+    fn share_hot_contracts(&mut self) {
+        let mut events: Vec<HotContractEvent> = self
+            .broadcast(|banhammer| banhammer.take_hot_contract_events())
+            .into_iter()
+            .flatten()
+            .collect();
+        if events.is_empty() {
+            return;
+        }
+        let hot: Vec<_> = events
+            .iter()
+            .map(|event| (event.contract, event.hot_until))
+            .collect();
+        self.broadcast(move |banhammer| {
+            for (contract, hot_until) in hot.iter() {
+                banhammer.mark_hot_contract(*contract, *hot_until);
+            }
+        });
+        self.hot_contract_events.append(&mut events);
+    }
+
+    /// Live leaky buckets count of all shards
+    pub fn live_buckets(&self) -> usize {
+        self.broadcast(|banhammer| banhammer.live_buckets())
+            .into_iter()
+            .sum()
+    }
+
+    /// Tick all shards for retention time and bans expiry,
+    /// set live buckets gauge to the total of shards.
+    /// Return: unban events for expired bans
+    pub fn tick(&mut self) -> Vec<UnbanEvent> {
+        let unban_events = self.sweep();
+        self.metrics
+            .set(Gauge::LiveBuckets, self.live_buckets() as i64);
+        unban_events
+    }
+
+    /// Free all shards from retained buckets and expired bans,
+    /// the same as `tick` but live buckets gauge isn't touched
+    /// Return: unban events for expired bans
+    pub fn sweep(&mut self) -> Vec<UnbanEvent> {
+        if let Some(config) = self.config.nonces.as_ref() {
+            self.nonces.retention_free(self.clock.now_secs(), config);
+        }
+        if let Some(max) = self.config.get_bucket_limit(&BucketIdentity::Address) {
+            self.nonces.limit(max);
+        }
+        self.broadcast(|banhammer| banhammer.sweep())
+            .into_iter()
+            .flatten()
+            .collect()
+    }
+
+    /// Read relayer input, process leaky buckets and return
+    /// ban events list, the same as `Banhammer::read_input`
+    pub fn read_input(&mut self, input: &RelayerMessage) -> Vec<BanEvent> {
+        self.read_inputs(std::slice::from_ref(input))
+    }
+
+    /// Read batch of relayer inputs, shards process their identity
+    /// values in parallel. Return: ban events list in messages order
+    pub fn read_inputs(&mut self, inputs: &[RelayerMessage]) -> Vec<BanEvent> {
+        let mut jobs: Vec<Vec<Job>> = self.workers.iter().map(|_| vec![]).collect();
+        let mut positions = 0;
+        // Positions range of every message, to correlate its ban events
+        let mut messages = vec![];
+        for input in inputs {
+            if !self.clock.observe(input.timestamp.as_duration()) {
+                tracing::debug!("late message dropped: {:?}", input.timestamp);
+                self.metrics.inc(Counter::LateMessages);
+                continue;
+            }
+            if !self.config.methods.evaluates(&input.method) {
+                continue;
+            }
+            let now = self.clock.now_secs();
+            let nonce_gap = self.nonces.track(input, self.config.nonces.as_ref(), now);
+            let start = positions;
+            let input = Arc::new(input.clone());
+            for (identity, value) in self.config.identities(&input) {
+                jobs[self.shard(&value)].push(Job {
+                    position: positions,
+                    input: input.clone(),
+                    nonce_gap,
+                    identity,
+                    value,
+                });
+                positions += 1;
+            }
+            messages.push((start..positions, input));
+        }
+
+        let tasks = jobs
+            .into_iter()
+            .enumerate()
+            .filter(|(_, jobs)| !jobs.is_empty())
+            .map(|(shard, jobs)| {
+                let task = move |banhammer: &mut Banhammer| {
+                    jobs.into_iter()
+                        .map(|job| {
+                            let events = banhammer.read_identity(
+                                &job.input,
+                                job.nonce_gap,
+                                job.identity,
+                                job.value,
+                            );
+                            (job.position, events)
+                        })
+                        .collect::<Vec<_>>()
+                };
+                (shard, Box::new(task) as Task<_>)
+            })
+            .collect();
+        let mut ban_events = vec![vec![]; positions];
+        for (position, events) in self.execute(tasks).into_iter().flatten() {
+            ban_events[position] = events;
+        }
+        self.share_hot_contracts();
+
+        let mut incidents = vec![];
+        for (range, input) in messages {
+            let mut events: Vec<BanEvent> = ban_events[range]
+                .iter_mut()
+                .flat_map(std::mem::take)
+                .collect();
+            correlate(&mut events, &input);
+            incidents.append(&mut events);
+        }
+        incidents
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::banhammer::LeakyBucketConfig;
+    use crate::buckets::{BucketConfig, BucketErrorKind};
+    use crate::clock::{EventTimeConfig, MockClock};
+    use crate::de::TransactionError;
+    use ethereum_types::Address;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::time::Duration;
+
+    fn relayer_message(sender: u8, contract: Address) -> RelayerMessage {
+        let mut message = RelayerMessage::test_message();
+        message.client = IpAddr::V4(Ipv4Addr::new(127, 0, 0, sender));
+        message.params.from = Address::from_low_u64_be(sender as u64);
+        message.params.to = Some(contract);
+        message.error = Some(TransactionError::Revert("revert".to_string()));
+        message
+    }
+
+    #[test]
+    fn test_sharded_banhammer() {
+        let bucket = BucketConfig {
+            base_size: 0,
+            leak_rate: 1,
+            overflow_size: None,
+            retention: Duration::from_secs(10),
+            ..BucketConfig::default()
+        };
+        let mut leaky_buckets = vec![];
+        for identity in [BucketIdentity::IP, BucketIdentity::Address] {
+            leaky_buckets.push(LeakyBucketConfig {
+                identity,
+                error_kind: BucketErrorKind::Reverts,
+                bucket,
+            });
+        }
+        leaky_buckets.push(LeakyBucketConfig {
+            identity: BucketIdentity::Contract,
+            error_kind: BucketErrorKind::Reverts,
+            bucket: BucketConfig {
+                overflow_size: Some(3),
+                ..bucket
+            },
+        });
+        let config = Config {
+            revert_threshold: 4,
+            leaky_buckets,
+            ..Config::default()
+        };
+        let contract = Address::from_low_u64_be(100);
+        let messages: Vec<_> = [1, 2, 3, 1, 1, 4, 4, 4, 4, 2, 2, 2]
+            .iter()
+            .map(|sender| relayer_message(*sender, contract))
+            .collect();
+
+        let clock = MockClock::default();
+        let mut bh = Banhammer::with_clock(config.clone(), clock.shared());
+        let mut sharded = ShardedBanhammer::with_clock(config.clone(), 4, clock.shared());
+        assert_eq!(sharded.shards(), 4);
+
+        // Message by message, the same events in the same order
+        for message in messages.iter() {
+            assert_eq!(bh.read_input(message), sharded.read_input(message));
+        }
+        assert_eq!(
+            bh.take_hot_contract_events(),
+            sharded.take_hot_contract_events()
+        );
+        assert_eq!(bh.live_buckets(), sharded.live_buckets());
+
+        // Batch is processed in parallel, hot contract
+        // is shared with shards after the batch
+        let mut sharded = ShardedBanhammer::with_clock(config.clone(), 4, clock.shared());
+        let events = sharded.read_inputs(&messages[..4]);
+        assert_eq!(events.len(), 0);
+        assert_eq!(sharded.take_hot_contract_events().len(), 1);
+        let events = sharded.read_inputs(&messages[4..]);
+        assert!(!events.is_empty());
+        assert!(events.iter().all(|event| event.threshold == 2));
+
+        let unban_value = events[0].value.clone();
+        assert!(sharded.unban(&unban_value).is_some());
+        assert!(sharded.unban(&unban_value).is_none());
+
+        // Shards read coordinator's event time
+        let config = Config {
+            event_time: Some(EventTimeConfig {
+                tolerance: Duration::from_secs(5),
+                idle_timeout: Duration::from_secs(60),
+            }),
+            ..config
+        };
+        let mut sharded = ShardedBanhammer::with_clock(config, 4, clock.shared());
+        let timestamp = messages[0].timestamp.as_duration().as_secs();
+        let events = sharded.read_inputs(&messages);
+        assert!(!events.is_empty());
+        assert!(events.iter().all(|event| event.banned_at == timestamp));
+    }
+
+    #[test]
+    fn test_stopped_shard() {
+        let sharded = ShardedBanhammer::new(Config::default(), 2);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            sharded.broadcast(|_| -> usize { panic!("shard failure") })
+        }));
+        assert!(result.is_err());
+        // Stopped shards aren't skipped with partial results
+        let result = panic::catch_unwind(AssertUnwindSafe(|| sharded.live_buckets()));
+        assert!(result.is_err());
+    }
+}
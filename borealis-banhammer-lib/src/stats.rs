@@ -1,18 +1,167 @@
-use crate::buckets::BucketName;
+use crate::buckets::{BucketErrorKind, BucketIdentity};
+use crate::levels::Level;
 use lazy_static::lazy_static;
 use prometheus::{
-    register_int_counter, register_int_counter_vec, Encoder, IntCounter, IntCounterVec,
+    core::Collector, Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    Opts, Registry,
 };
+use std::sync::Arc;
 
 lazy_static! {
-    static ref TOTAL_MESSAGES_RECEIVED: IntCounter =
-        register_int_counter!("Total_messages_received", "Total messages received").unwrap();
-    static ref TOTAL_MESSAGES_PROCESSED: IntCounter =
-        register_int_counter!("Total_messages_processed", "Total messages processed").unwrap();
-    static ref TOTAL_MESSAGES_SENT: IntCounter =
-        register_int_counter!("Total_messages_sent", "Total messages sent").unwrap();
-    static ref TOTAL_BAN_REAS0N: IntCounterVec =
-        register_int_counter_vec!("Total_ban_reason", "Total ban reason", &["reason"]).unwrap();
+    /// Live series in default registry
+    static ref LIVE: Series = Series::register(prometheus::default_registry());
+    /// Candidate config series of shadow evaluation
+    static ref SHADOW_REGISTRY: Registry =
+        Registry::new_custom(Some("shadow".to_string()), None).unwrap();
+    static ref SHADOW: Series = Series::register(&SHADOW_REGISTRY);
+}
+
+/// Register metric in registry
+fn register<M: Collector + Clone + 'static>(registry: &Registry, metric: M) -> M {
+    registry.register(Box::new(metric.clone())).unwrap();
+    metric
+}
+
+fn int_counter(registry: &Registry, name: &str, help: &str) -> IntCounter {
+    register(registry, IntCounter::new(name, help).unwrap())
+}
+
+fn int_counter_vec(registry: &Registry, name: &str, help: &str, labels: &[&str]) -> IntCounterVec {
+    register(
+        registry,
+        IntCounterVec::new(Opts::new(name, help), labels).unwrap(),
+    )
+}
+
+/// Prometheus series of registry
+pub(crate) struct Series {
+    total_messages_received: IntCounter,
+    total_messages_processed: IntCounter,
+    total_messages_sent: IntCounter,
+    total_unbans: IntCounter,
+    total_ban_reason: IntCounterVec,
+    total_evicted_buckets: IntCounter,
+    total_hot_contracts: IntCounter,
+    total_shadow_messages_sent: IntCounter,
+    total_would_ban_reason: IntCounterVec,
+    total_capped_buckets: IntCounterVec,
+    total_suppressed_bans: IntCounterVec,
+    total_ban_level: IntCounterVec,
+    total_late_messages: IntCounter,
+    live_buckets: IntGauge,
+    reputation_score: HistogramVec,
+}
+
+impl Series {
+    fn register(registry: &Registry) -> Self {
+        Self {
+            total_messages_received: int_counter(
+                registry,
+                "Total_messages_received",
+                "Total messages received",
+            ),
+            total_messages_processed: int_counter(
+                registry,
+                "Total_messages_processed",
+                "Total messages processed",
+            ),
+            total_messages_sent: int_counter(
+                registry,
+                "Total_messages_sent",
+                "Total messages sent",
+            ),
+            total_unbans: int_counter(registry, "Total_unbans", "Total unbans"),
+            total_ban_reason: int_counter_vec(
+                registry,
+                "Total_ban_reason",
+                "Total ban reason",
+                &["identity", "reason"],
+            ),
+            total_evicted_buckets: int_counter(
+                registry,
+                "Total_evicted_buckets",
+                "Total evicted buckets",
+            ),
+            total_hot_contracts: int_counter(
+                registry,
+                "Total_hot_contracts",
+                "Total hot contract alerts",
+            ),
+            total_shadow_messages_sent: int_counter(
+                registry,
+                "Total_shadow_messages_sent",
+                "Total shadow messages sent",
+            ),
+            total_would_ban_reason: int_counter_vec(
+                registry,
+                "Total_would_ban_reason",
+                "Total would-ban reason of candidate config",
+                &["identity", "reason"],
+            ),
+            total_capped_buckets: int_counter_vec(
+                registry,
+                "Total_capped_buckets",
+                "Total buckets evicted over live buckets cap",
+                &["identity"],
+            ),
+            total_suppressed_bans: int_counter_vec(
+                registry,
+                "Total_suppressed_bans",
+                "Total duplicate bans of already banned values",
+                &["identity", "reason"],
+            ),
+            total_ban_level: int_counter_vec(
+                registry,
+                "Total_ban_level",
+                "Total ban events by level",
+                &["level"],
+            ),
+            total_late_messages: int_counter(
+                registry,
+                "Total_late_messages",
+                "Total late messages dropped",
+            ),
+            live_buckets: register(
+                registry,
+                IntGauge::new("Live_buckets", "Live buckets").unwrap(),
+            ),
+            reputation_score: register(
+                registry,
+                HistogramVec::new(
+                    HistogramOpts::new("Reputation_score", "Reputation score of identity values")
+                        .buckets(vec![
+                            -100.0, -50.0, -20.0, -10.0, -5.0, -1.0, 0.0, 1.0, 5.0, 10.0, 20.0,
+                            50.0, 100.0,
+                        ]),
+                    &["identity"],
+                )
+                .unwrap(),
+            ),
+        }
+    }
+
+    /// Current value of capped buckets counter
+    #[cfg(test)]
+    pub(crate) fn capped_buckets(&self, identity: &BucketIdentity) -> u64 {
+        self.total_capped_buckets
+            .with_label_values(&[format!("{:?}", identity).as_str()])
+            .get()
+    }
+
+    /// Current value of suppressed bans counter
+    #[cfg(test)]
+    pub(crate) fn suppressed_bans(
+        &self,
+        identity: &BucketIdentity,
+        reason: &BucketErrorKind,
+    ) -> u64 {
+        self.total_suppressed_bans
+            .with_label_values(&[
+                format!("{:?}", identity).as_str(),
+                format!("{:?}", reason).as_str(),
+            ])
+            .get()
+    }
 }
 
 /// Statistic counter kinds
@@ -20,19 +169,90 @@ pub enum Counter {
     MessagesReceived,
     MessagesProcessed,
     MessagesSent,
-    BanReason(BucketName),
+    Unbans,
+    BanReason(BucketIdentity, BucketErrorKind),
+    EvictedBuckets,
+    HotContracts,
+    ShadowMessagesSent,
+    WouldBanReason(BucketIdentity, BucketErrorKind),
+    CappedBuckets(BucketIdentity),
+    LateMessages,
+    SuppressedBans(BucketIdentity, BucketErrorKind),
+    BanLevel(Level),
 }
 
 impl Counter {
-    /// Increment specific counter
-    fn inc(&self) {
+    /// Increment specific counter by value
+    fn inc_by(&self, series: &Series, value: u64) {
+        match self {
+            Self::MessagesReceived => series.total_messages_received.inc_by(value),
+            Self::MessagesProcessed => series.total_messages_processed.inc_by(value),
+            Self::MessagesSent => series.total_messages_sent.inc_by(value),
+            Self::Unbans => series.total_unbans.inc_by(value),
+            Self::BanReason(identity, reason) => series
+                .total_ban_reason
+                .with_label_values(&[
+                    format!("{:?}", identity).as_str(),
+                    format!("{:?}", reason).as_str(),
+                ])
+                .inc_by(value),
+            Self::EvictedBuckets => series.total_evicted_buckets.inc_by(value),
+            Self::HotContracts => series.total_hot_contracts.inc_by(value),
+            Self::ShadowMessagesSent => series.total_shadow_messages_sent.inc_by(value),
+            Self::WouldBanReason(identity, reason) => series
+                .total_would_ban_reason
+                .with_label_values(&[
+                    format!("{:?}", identity).as_str(),
+                    format!("{:?}", reason).as_str(),
+                ])
+                .inc_by(value),
+            Self::CappedBuckets(identity) => series
+                .total_capped_buckets
+                .with_label_values(&[format!("{:?}", identity).as_str()])
+                .inc_by(value),
+            Self::LateMessages => series.total_late_messages.inc_by(value),
+            Self::SuppressedBans(identity, reason) => series
+                .total_suppressed_bans
+                .with_label_values(&[
+                    format!("{:?}", identity).as_str(),
+                    format!("{:?}", reason).as_str(),
+                ])
+                .inc_by(value),
+            Self::BanLevel(level) => series
+                .total_ban_level
+                .with_label_values(&[format!("{:?}", level).as_str()])
+                .inc_by(value),
+        }
+    }
+}
+
+/// Statistic gauge kinds
+pub enum Gauge {
+    LiveBuckets,
+}
+
+impl Gauge {
+    /// Set specific gauge
+    fn set(&self, series: &Series, value: i64) {
+        match self {
+            Self::LiveBuckets => series.live_buckets.set(value),
+        }
+    }
+}
+
+/// Statistic histogram kinds
+pub enum Histogram {
+    ReputationScore(BucketIdentity),
+}
+
+impl Histogram {
+    /// Observe value of specific histogram
+    fn observe(&self, series: &Series, value: f64) {
         match self {
-            Self::MessagesReceived => TOTAL_MESSAGES_RECEIVED.inc(),
-            Self::MessagesProcessed => TOTAL_MESSAGES_PROCESSED.inc(),
-            Self::MessagesSent => TOTAL_MESSAGES_SENT.inc(),
-            Self::BanReason(reason) => TOTAL_BAN_REAS0N
-                .with_label_values(&[format!("{:?}", reason).as_str()])
-                .inc(),
+            Self::ReputationScore(identity) => series
+                .reputation_score
+                .with_label_values(&[format!("{:?}", identity).as_str()])
+                .observe(value),
         }
     }
 }
@@ -43,16 +263,114 @@ pub struct Measure;
 impl Measure {
     /// Increment specific counter
     pub fn inc(counter: Counter) {
-        counter.inc();
+        counter.inc_by(&LIVE, 1);
     }
 
-    /// Gather metrics
+    /// Increment specific counter by value
+    pub fn inc_by(counter: Counter, value: u64) {
+        counter.inc_by(&LIVE, value);
+    }
+
+    /// Set specific gauge
+    pub fn set(gauge: Gauge, value: i64) {
+        gauge.set(&LIVE, value);
+    }
+
+    /// Observe histogram value
+    pub fn observe(histogram: Histogram, value: f64) {
+        histogram.observe(&LIVE, value);
+    }
+
+    /// Gather metrics, live and shadow series
     pub fn gather() -> Vec<u8> {
         let encoder = prometheus::TextEncoder::new();
-        let metric_families = prometheus::gather();
+        let mut metric_families = prometheus::gather();
+        metric_families.append(&mut SHADOW_REGISTRY.gather());
         let mut buffer = vec![];
         encoder.encode(&metric_families, &mut buffer).unwrap();
 
         buffer
     }
 }
+
+/// Metrics sink of Banhammer
+pub trait Metrics: Send + Sync {
+    /// Increment specific counter by value
+    fn inc_by(&self, counter: Counter, value: u64);
+
+    /// Set specific gauge
+    fn set(&self, gauge: Gauge, value: i64);
+
+    /// Observe histogram value
+    fn observe(&self, histogram: Histogram, value: f64);
+
+    /// Increment specific counter
+    fn inc(&self, counter: Counter) {
+        self.inc_by(counter, 1)
+    }
+}
+
+/// Metrics sink shared by Banhammer components
+pub type SharedMetrics = Arc<dyn Metrics>;
+
+/// Live series, the same as `Measure`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LiveMetrics;
+
+impl LiveMetrics {
+    pub fn shared() -> SharedMetrics {
+        Arc::new(LiveMetrics)
+    }
+}
+
+impl Metrics for LiveMetrics {
+    fn inc_by(&self, counter: Counter, value: u64) {
+        counter.inc_by(&LIVE, value);
+    }
+
+    fn set(&self, gauge: Gauge, value: i64) {
+        gauge.set(&LIVE, value);
+    }
+
+    fn observe(&self, histogram: Histogram, value: f64) {
+        histogram.observe(&LIVE, value);
+    }
+}
+
+/// Series of candidate config in shadow evaluation,
+/// names are prefixed with `shadow_`, so live
+/// dashboards and alerts aren't affected
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ShadowMetrics;
+
+impl ShadowMetrics {
+    pub fn shared() -> SharedMetrics {
+        Arc::new(ShadowMetrics)
+    }
+}
+
+impl Metrics for ShadowMetrics {
+    fn inc_by(&self, counter: Counter, value: u64) {
+        counter.inc_by(&SHADOW, value);
+    }
+
+    fn set(&self, gauge: Gauge, value: i64) {
+        gauge.set(&SHADOW, value);
+    }
+
+    fn observe(&self, histogram: Histogram, value: f64) {
+        histogram.observe(&SHADOW, value);
+    }
+}
+
+/// Live series, read by tests
+#[cfg(test)]
+pub(crate) fn live_series() -> &'static Series {
+    &LIVE
+}
+
+/// Shadow series, read by tests
+#[cfg(test)]
+pub(crate) fn shadow_series() -> &'static Series {
+    &SHADOW
+}
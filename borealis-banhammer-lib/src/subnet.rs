@@ -0,0 +1,210 @@
+//! # Subnet
+//!
+//! IP ranges in CIDR notation and subnet identities.
+//! Client IP is aggregated to IPv4 /24 and IPv6 /64
+//! by default, IPv4-mapped IPv6 address is treated
+//! as IPv4 address.
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    fmt,
+    net::{IpAddr, Ipv4Addr},
+    str::FromStr,
+};
+
+/// IP range in CIDR notation, single IP is full-length prefix.
+/// Host bits of the address are cleared.
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, String> {
+        let addr = match addr {
+            IpAddr::V4(addr) if prefix <= 32 => {
+                IpAddr::V4((u32::from(addr) & Self::mask_v4(prefix)).into())
+            }
+            IpAddr::V6(addr) if prefix <= 128 => {
+                IpAddr::V6((u128::from(addr) & Self::mask_v6(prefix)).into())
+            }
+            _ => return Err(format!("prefix /{prefix} is too long for {addr}")),
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    fn mask_v4(prefix: u8) -> u32 {
+        u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0)
+    }
+
+    fn mask_v6(prefix: u8) -> u128 {
+        u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0)
+    }
+
+    /// Network address
+    pub fn addr(&self) -> IpAddr {
+        self.addr
+    }
+
+    /// Prefix length
+    pub fn prefix(&self) -> u8 {
+        self.prefix
+    }
+
+    /// Is IP in the range
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.addr, normalize(*ip)) {
+            (IpAddr::V4(addr), IpAddr::V4(ip)) => {
+                u32::from(addr) == u32::from(ip) & Self::mask_v4(self.prefix)
+            }
+            (IpAddr::V6(addr), IpAddr::V6(ip)) => {
+                u128::from(addr) == u128::from(ip) & Self::mask_v6(self.prefix)
+            }
+            _ => false,
+        }
+    }
+
+    /// Is subnet in the range
+    pub fn contains_subnet(&self, subnet: &Cidr) -> bool {
+        self.prefix <= subnet.prefix && self.contains(&subnet.addr)
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|e| format!("{e}: {s}"))?;
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|e| format!("{e}: {s}"))?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        Self::new(addr, prefix)
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cidr {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for Cidr {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+/// IPv4-mapped IPv6 address as IPv4 address
+pub fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(addr) => match addr.octets() {
+            [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, a, b, c, d] => {
+                IpAddr::V4(Ipv4Addr::new(a, b, c, d))
+            }
+            _ => ip,
+        },
+        ip => ip,
+    }
+}
+
+/// Subnet identities config
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct SubnetConfig {
+    #[serde(default = "default_ipv4_prefix")]
+    pub ipv4_prefix: u8,
+    #[serde(default = "default_ipv6_prefix")]
+    pub ipv6_prefix: u8,
+}
+
+fn default_ipv4_prefix() -> u8 {
+    24
+}
+
+fn default_ipv6_prefix() -> u8 {
+    64
+}
+
+impl Default for SubnetConfig {
+    fn default() -> Self {
+        Self {
+            ipv4_prefix: default_ipv4_prefix(),
+            ipv6_prefix: default_ipv6_prefix(),
+        }
+    }
+}
+
+impl SubnetConfig {
+    /// Subnet of client IP, prefix is limited by address length
+    pub fn subnet(&self, ip: IpAddr) -> Cidr {
+        let ip = normalize(ip);
+        let prefix = match ip {
+            IpAddr::V4(_) => self.ipv4_prefix.min(32),
+            IpAddr::V6(_) => self.ipv6_prefix.min(128),
+        };
+        Cidr::new(ip, prefix).unwrap_or(Cidr {
+            addr: ip,
+            prefix: 0,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_subnet() {
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        let config = SubnetConfig::default();
+        assert_eq!(config.subnet(ip("1.2.3.4")).to_string(), "1.2.3.0/24");
+        assert_eq!(
+            config.subnet(ip("::ffff:1.2.3.4")),
+            config.subnet(ip("1.2.3.200"))
+        );
+        assert_eq!(
+            config.subnet(ip("2001:db8:1:2:3:4:5:6")).to_string(),
+            "2001:db8:1:2::/64"
+        );
+        let config = SubnetConfig {
+            ipv4_prefix: 16,
+            ipv6_prefix: 200,
+        };
+        assert_eq!(config.subnet(ip("1.2.3.4")).to_string(), "1.2.0.0/16");
+        assert_eq!(config.subnet(ip("::1")).prefix(), 128);
+
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains(&ip("10.1.2.3")));
+        assert!(!cidr.contains(&ip("11.1.2.3")));
+        assert!(cidr.contains(&ip("::ffff:10.1.2.3")));
+        assert!(cidr.contains_subnet(&config.subnet(ip("10.1.2.3"))));
+        assert!(!"10.1.2.3/32"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains_subnet(&config.subnet(ip("10.1.2.3"))));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("0.0.0.0/0"
+            .parse::<Cidr>()
+            .unwrap()
+            .contains(&ip("1.2.3.4")));
+    }
+}
@@ -0,0 +1,203 @@
+//! # Token tiers
+//!
+//! Token registry maps API tokens to named tiers, each
+//! tier has its own thresholds multiplier and bucket
+//! configs. Registry is loaded from `Tokens.toml` file:
+//!
+//! ```toml
+//! [tokens]
+//! "<token>" = "enterprise"
+//! ```
+//!
+//! Unknown and malformed tokens get `unknown_tier`.
+use crate::banhammer::LeakyBucketConfig;
+use crate::buckets::{BucketConfig, BucketErrorKind, BucketIdentity};
+use crate::de::Token;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::HashMap;
+
+/// Token tier
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TokenTier {
+    pub name: String,
+    /// Bucket thresholds multiplier
+    #[serde(default = "default_multiplier")]
+    pub multiplier: u64,
+    /// Bucket configs of the tier, thresholds
+    /// of these buckets aren't multiplied
+    #[serde(default)]
+    pub leaky_buckets: Vec<LeakyBucketConfig>,
+}
+
+fn default_multiplier() -> u64 {
+    1
+}
+
+impl TokenTier {
+    /// Bucket config of the tier
+    pub fn get_bucket_config(
+        &self,
+        kind: &BucketIdentity,
+        err: &BucketErrorKind,
+    ) -> Option<BucketConfig> {
+        self.leaky_buckets
+            .iter()
+            .find(|config| &config.identity == kind && &config.error_kind == err)
+            .map(|config| config.bucket)
+    }
+}
+
+/// Token tiers config, `unknown_tier` is checked
+/// to be one of `tiers` on config load
+#[derive(Debug, Serialize, Clone)]
+pub struct TokenTiersConfig {
+    pub tiers: Vec<TokenTier>,
+    /// Tier of tokens missing in registry and malformed tokens
+    pub unknown_tier: String,
+}
+
+/// Token tiers config as it's written in config
+#[derive(Deserialize)]
+struct RawTokenTiersConfig {
+    tiers: Vec<TokenTier>,
+    unknown_tier: String,
+}
+
+impl<'de> Deserialize<'de> for TokenTiersConfig {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let RawTokenTiersConfig {
+            tiers,
+            unknown_tier,
+        } = RawTokenTiersConfig::deserialize(deserializer)?;
+        if !tiers.iter().any(|tier| tier.name == unknown_tier) {
+            return Err(serde::de::Error::custom(format!(
+                "unknown tier {:?} is missing in token tiers",
+                unknown_tier
+            )));
+        }
+        Ok(Self {
+            tiers,
+            unknown_tier,
+        })
+    }
+}
+
+impl TokenTiersConfig {
+    /// Tier by name
+    pub fn get(&self, name: &str) -> Option<&TokenTier> {
+        self.tiers.iter().find(|tier| tier.name == name)
+    }
+
+    /// Tier of token registered in registry, unknown tier by default
+    pub fn tier(&self, token: &Token, registry: &TokenRegistry) -> Option<&TokenTier> {
+        let name = match registry.get(token) {
+            Some(name) if token.is_valid() => name,
+            _ => self.unknown_tier.as_str(),
+        };
+        self.get(name)
+    }
+}
+
+/// Token => tier name registry
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct TokenRegistry {
+    #[serde(default)]
+    tokens: HashMap<String, String>,
+}
+
+impl TokenRegistry {
+    pub fn new(tokens: HashMap<String, String>) -> Self {
+        Self { tokens }
+    }
+
+    /// Tier name of token
+    pub fn get(&self, token: &Token) -> Option<&str> {
+        self.tokens.get(token.as_str()).map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_tiers() {
+        #[derive(Deserialize)]
+        struct Config {
+            token_tiers: TokenTiersConfig,
+        }
+        let Config { token_tiers } = toml::from_str(
+            r#"
+[token_tiers]
+unknown_tier = "anonymous"
+
+[[token_tiers.tiers]]
+name = "anonymous"
+
+[[token_tiers.tiers]]
+name = "free"
+multiplier = 2
+
+[[token_tiers.tiers]]
+name = "enterprise"
+multiplier = 100
+[[token_tiers.tiers.leaky_buckets]]
+identity = "Token"
+error_kind = "Reverts"
+bucket = { base_size = 0, leak_rate = 1, overflow_size = 5000, retention = 60 }
+"#,
+        )
+        .unwrap();
+        let free = Token::new("Zm9vYmFyZm9vYmFyZm9vYmFyZm9vYmFyZm9vYmFyZm9v");
+        let partner = Token::new("YmF6YmF6YmF6YmF6YmF6YmF6YmF6YmF6YmF6YmF6YmF6");
+        let unknown = Token::new("cXV4cXV4cXV4cXV4cXV4cXV4cXV4cXV4cXV4cXV4cXV4");
+        let malformed = Token::new("partner");
+        let registry: TokenRegistry = toml::from_str(&format!(
+            r#"
+[tokens]
+"{}" = "free"
+"{}" = "enterprise"
+"partner" = "enterprise"
+"#,
+            free.as_str(),
+            partner.as_str()
+        ))
+        .unwrap();
+        assert_eq!(registry.len(), 3);
+
+        let tier = |token| token_tiers.tier(token, &registry).unwrap();
+        assert_eq!(tier(&free).multiplier, 2);
+        assert_eq!(tier(&partner).name, "enterprise");
+        assert_eq!(tier(&unknown).name, "anonymous");
+        assert_eq!(tier(&malformed).name, "anonymous");
+        assert_eq!(
+            tier(&partner)
+                .get_bucket_config(&BucketIdentity::Token, &BucketErrorKind::Reverts)
+                .and_then(|config| config.overflow_size),
+            Some(5000)
+        );
+
+        // Unknown tier must be one of tiers
+        assert!(toml::from_str::<Config>(
+            r#"
+[token_tiers]
+unknown_tier = "anonymous"
+
+[[token_tiers.tiers]]
+name = "free"
+"#,
+        )
+        .is_err());
+    }
+}
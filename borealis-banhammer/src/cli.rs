@@ -71,6 +71,13 @@ pub(crate) struct Context {
     /// Stream banning event messages to subject
     #[clap(long, default_value = "banhammer.ban.messages")]
     pub tx_subject: String,
+    /// Stream would-ban event messages of candidate (shadow) configuration to subject
+    #[clap(long, default_value = "banhammer.shadow.messages")]
+    pub shadow_tx_subject: String,
+    /// Banhammer shards (worker threads) count for leaky buckets processing.
+    /// Defaults to the number of CPU cores
+    #[clap(long)]
+    pub shards: Option<usize>,
 }
 
 /// Verbosity level for messages dump to log and stdout:
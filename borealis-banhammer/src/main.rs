@@ -1,12 +1,19 @@
-use actix;
+use borealis_banhammer_lib::{
+    banhammer,
+    de::RelayerMessage,
+    events::Event,
+    levels::Level,
+    shards::ShardedBanhammer,
+    stats::{Counter, LiveMetrics, Measure, ShadowMetrics, SharedMetrics},
+    tiers::TokenRegistry,
+};
 use clap::Parser;
 use cli::{
     init_logging, Error, Opts, Context, SubCommand, VerbosityLevel,
 };
 use core::sync::atomic::{AtomicUsize, Ordering};
-use nats;
 use serde::{Deserialize, Serialize};
-use serde_json;
+use std::sync::Arc;
 use tokio::runtime::{Runtime, Builder};
 use tokio::signal::{ctrl_c, unix::{signal, SignalKind}};
 use tokio::sync::{mpsc, watch};
@@ -99,20 +106,251 @@ async fn key_switch() -> Result<(), Error> {
     Ok(())
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct BanhammerBanEventMessage;
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct BanhammerConfigMessage;
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct RelayerMessage;
+/// Max relayer messages processed by ban manager at once
+const RELAYER_MESSAGES_BATCH_SIZE: usize = 1000;
+/// Ban manager tick period for bans expiry and buckets retention, independent of relayer messages traffic
+const BAN_MANAGER_TICK_INTERVAL: core::time::Duration = core::time::Duration::from_secs(1);
+
+type BanhammerBanEventMessage = Event;
+type BanhammerConfigMessage = banhammer::Config;
 #[derive(Debug, Serialize, Deserialize, Clone)]
 struct EthCallMessage;
 
+/// Load Banhammer's leaky buckets configuration from home directory
+fn load_config(home_dir: &std::path::Path) -> Result<banhammer::Config, Error> {
+    let config_path = home_dir.join("Config.toml");
+    let raw_toml = std::fs::read_to_string(&config_path)
+        .map_err(|error| format!("Missing or unreadable config file {:?}: {:?}", config_path, error))?;
+    let config = toml::from_str::<banhammer::Config>(&raw_toml)
+        .map_err(|error| format!("Failed to parse config file {:?}: {:?}", config_path, error))?;
+    Ok(config)
+}
+
+/// Load token registry (token => tier name) from home directory,
+/// registry is empty if the file doesn't exist
+fn load_token_registry(home_dir: &std::path::Path) -> Result<TokenRegistry, Error> {
+    let registry_path = home_dir.join("Tokens.toml");
+    if !registry_path.exists() {
+        return Ok(TokenRegistry::default());
+    }
+    let raw_toml = std::fs::read_to_string(&registry_path)
+        .map_err(|error| format!("Unreadable token registry file {:?}: {:?}", registry_path, error))?;
+    let registry = toml::from_str::<TokenRegistry>(&raw_toml)
+        .map_err(|error| format!("Failed to parse token registry file {:?}: {:?}", registry_path, error))?;
+    Ok(registry)
+}
+
+/// Load candidate leaky buckets configuration for shadow (dry-run) evaluation from home directory,
+/// shadow mode is disabled if the file doesn't exist
+fn load_shadow_config(home_dir: &std::path::Path) -> Result<Option<banhammer::Config>, Error> {
+    let config_path = home_dir.join("ShadowConfig.toml");
+    if !config_path.exists() {
+        return Ok(None);
+    }
+    let raw_toml = std::fs::read_to_string(&config_path)
+        .map_err(|error| format!("Unreadable shadow config file {:?}: {:?}", config_path, error))?;
+    let config = toml::from_str::<banhammer::Config>(&raw_toml)
+        .map_err(|error| format!("Failed to parse shadow config file {:?}: {:?}", config_path, error))?;
+    Ok(Some(config))
+}
+
+/// Banhammer with leaky buckets partitioned by identity value across shards (worker threads),
+/// its metrics are written to `metrics` sink
+fn sharded_ban_manager(config: banhammer::Config, token_registry: &TokenRegistry, shards: usize, metrics: SharedMetrics) -> ShardedBanhammer {
+    let mut ban_manager = ShardedBanhammer::new(config, shards);
+    ban_manager.update_token_registry(token_registry.clone());
+    ban_manager.set_metrics(metrics);
+    ban_manager
+}
+
+/// Run blocking call of sharded Banhammer on Tokio's blocking threads, so async run-time isn't stalled while
+/// shards process relayer messages. Stopped shard stops the system, which is restarted with new ban manager
+async fn run_blocking<T, F>(mut ban_manager: ShardedBanhammer, call: F) -> Option<(ShardedBanhammer, T)>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut ShardedBanhammer) -> T + Send + 'static,
+{
+    let result = tokio::task::spawn_blocking(move || {
+        let result = call(&mut ban_manager);
+        (ban_manager, result)
+    })
+    .await;
+    match result {
+        Ok(result) => Some(result),
+        Err(error) => {
+            error!(target: "borealis_banhammer_buckets", "Ban manager: Banhammer shards failure, system will be restarted: {:?}", error);
+            actix::System::current().stop_with_code(1);
+            None
+        }
+    }
+}
+
+async fn ban_manager(
+    mut relayer_message_stream_rx: mpsc::Receiver<RelayerMessage>,
+    mut config_message_stream_rx: mpsc::Receiver<BanhammerConfigMessage>,
+    ban_event_stream_tx: mpsc::Sender<BanhammerBanEventMessage>,
+    shadow_event_stream_tx: mpsc::Sender<BanhammerBanEventMessage>,
+    mut ban_manager: ShardedBanhammer,
+    // Candidate configuration evaluated on the same relayer messages stream, its bans are only published as would-ban events
+    mut shadow_ban_manager: Option<ShardedBanhammer>,
+    // Token registry is reloaded from home directory with every configuration update
+    home_dir: std::path::PathBuf,
+) {
+    info!(target: "borealis_banhammer_buckets", "Ban manager: leaky buckets are processed by {} shards", ban_manager.shards());
+    if shadow_ban_manager.is_some() {
+        info!(target: "borealis_banhammer_buckets", "Ban manager: shadow mode enabled: candidate configuration is evaluated alongside the live one");
+    }
+
+    info!(
+        target: "borealis_banhammer_buckets",
+        "Ban manager loop starting: processing relayer messages with leaky buckets\n"
+    );
+
+    let mut tick_interval = tokio::time::interval(BAN_MANAGER_TICK_INTERVAL);
+    tick_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            // Configuration updates are applied before queued relayer messages, and on idle relayer messages stream as well.
+            // Tick goes before relayer messages, so it isn't starved by busy stream
+            biased;
+
+            Some(config_message) = config_message_stream_rx.recv() => {
+                info!(target: "borealis_banhammer_buckets", "Ban manager: leaky buckets configuration updated");
+                (ban_manager, _) = match run_blocking(ban_manager, move |ban_manager| ban_manager.update_config(config_message)).await {
+                    Some(result) => result,
+                    None => return,
+                };
+                // Reload token registry, so tokens tiers of updated configuration don't wait for restart.
+                // Previous registry is kept if the file can't be loaded
+                match load_token_registry(&home_dir) {
+                    Ok(token_registry) => {
+                        info!(target: "borealis_banhammer_buckets", "Ban manager: token registry reloaded, {} tokens", token_registry.len());
+                        if let Some(candidate_ban_manager) = shadow_ban_manager.take() {
+                            let candidate_token_registry = token_registry.clone();
+                            shadow_ban_manager = match run_blocking(candidate_ban_manager, move |ban_manager| ban_manager.update_token_registry(candidate_token_registry)).await {
+                                Some((candidate_ban_manager, _)) => Some(candidate_ban_manager),
+                                None => return,
+                            };
+                        }
+                        (ban_manager, _) = match run_blocking(ban_manager, move |ban_manager| ban_manager.update_token_registry(token_registry)).await {
+                            Some(result) => result,
+                            None => return,
+                        };
+                    }
+                    Err(error) => {
+                        error!(target: "borealis_banhammer_buckets", "Ban manager: token registry reload error, previous registry is kept: {:?}", error);
+                    }
+                }
+            }
+            // Bans expire and unban events are published on idle relayer messages stream as well
+            _ = tick_interval.tick() => {
+                let unban_events;
+                (ban_manager, unban_events) = match run_blocking(ban_manager, |ban_manager| ban_manager.tick()).await {
+                    Some(result) => result,
+                    None => return,
+                };
+                for unban_event in unban_events {
+                    info!(target: "borealis_banhammer_buckets", "Unban event: {:?}", unban_event);
+                    Measure::inc(Counter::Unbans);
+                    ban_event_stream_tx
+                        .send(Event::Unban(unban_event))
+                        .await
+                        .unwrap_or_else(|error|
+                            error!(target: "borealis_banhammer_buckets", "Ban manager: Unban event message send error: {:?}", error)
+                        );
+                }
+
+                // Candidate's unbans only affect its own state, live buckets gauge is left to the live ban manager
+                if let Some(candidate_ban_manager) = shadow_ban_manager.take() {
+                    shadow_ban_manager = match run_blocking(candidate_ban_manager, |ban_manager| ban_manager.sweep()).await {
+                        Some((candidate_ban_manager, _)) => Some(candidate_ban_manager),
+                        None => return,
+                    };
+                }
+            }
+            relayer_message = relayer_message_stream_rx.recv() => {
+                let relayer_message = match relayer_message {
+                    Some(relayer_message) => relayer_message,
+                    None => break,
+                };
+                // Relayer messages queued since the previous batch are processed by shards in parallel
+                let mut relayer_messages = vec![relayer_message];
+                while relayer_messages.len() < RELAYER_MESSAGES_BATCH_SIZE {
+                    match relayer_message_stream_rx.try_recv() {
+                        Ok(relayer_message) => relayer_messages.push(relayer_message),
+                        Err(_) => break,
+                    }
+                }
+                Measure::inc_by(Counter::MessagesReceived, relayer_messages.len() as u64);
+                let relayer_messages = Arc::new(relayer_messages);
+
+                // Read relayer messages and process leaky buckets.
+                // As result - Ban Events
+                let batch = relayer_messages.clone();
+                let ban_events;
+                (ban_manager, ban_events) = match run_blocking(ban_manager, move |ban_manager| ban_manager.read_inputs(&batch)).await {
+                    Some(result) => result,
+                    None => return,
+                };
+                debug!(target: "borealis_banhammer_buckets", "Ban manager: ban events count: {}", ban_events.len());
+                for ban_event in ban_events {
+                    info!(target: "borealis_banhammer_buckets", "Ban event: {:?}", ban_event);
+                    Measure::inc(Counter::BanLevel(ban_event.level));
+                    if let Level::Ban = ban_event.level {
+                        Measure::inc(Counter::BanReason(ban_event.identity.clone(), ban_event.error.clone()));
+                    }
+                    ban_event_stream_tx
+                        .send(Event::from(ban_event))
+                        .await
+                        .unwrap_or_else(|error|
+                            error!(target: "borealis_banhammer_buckets", "Ban manager: Ban event message send error: {:?}", error)
+                        );
+                }
+                for hot_contract_event in ban_manager.take_hot_contract_events() {
+                    info!(target: "borealis_banhammer_buckets", "Hot contract event: {:?}", hot_contract_event);
+                    Measure::inc(Counter::HotContracts);
+                    ban_event_stream_tx
+                        .send(Event::HotContract(hot_contract_event))
+                        .await
+                        .unwrap_or_else(|error|
+                            error!(target: "borealis_banhammer_buckets", "Ban manager: Hot contract event message send error: {:?}", error)
+                        );
+                }
+                Measure::inc_by(Counter::MessagesProcessed, relayer_messages.len() as u64);
+
+                // Shadow (dry-run) evaluation of candidate configuration
+                if let Some(candidate_ban_manager) = shadow_ban_manager.take() {
+                    let batch = relayer_messages.clone();
+                    let (mut candidate_ban_manager, would_ban_events) = match run_blocking(candidate_ban_manager, move |ban_manager| ban_manager.read_inputs(&batch)).await {
+                        Some(result) => result,
+                        None => return,
+                    };
+                    for would_ban_event in would_ban_events {
+                        info!(target: "borealis_banhammer_buckets", "Would-ban event: {:?}", would_ban_event);
+                        if let Level::Ban = would_ban_event.level {
+                            Measure::inc(Counter::WouldBanReason(would_ban_event.identity.clone(), would_ban_event.error.clone()));
+                        }
+                        shadow_event_stream_tx
+                            .send(Event::WouldBan(would_ban_event))
+                            .await
+                            .unwrap_or_else(|error|
+                                error!(target: "borealis_banhammer_buckets", "Ban manager: Would-ban event message send error: {:?}", error)
+                            );
+                    }
+                    // Candidate's hot contracts only affect its own state
+                    candidate_ban_manager.take_hot_contract_events();
+                    shadow_ban_manager = Some(candidate_ban_manager);
+                }
+            }
+        }
+    }
+}
+
 async fn message_producer(
     mut events_stream: mpsc::Receiver<BanhammerBanEventMessage>,
     actual_connection_rx: watch::Receiver<NATSConnection>,
     connection_event_tx: mpsc::Sender<ConnectionEvent>,
-    context: Context,
+    tx_subject: String,
     verbosity_level: Option<VerbosityLevel>,
 ) {
     info!(
@@ -133,13 +371,18 @@ async fn message_producer(
 
             let result = nats_connection.connection.as_ref().unwrap()
                 .publish(
-                    context.tx_subject.as_str(),
+                    tx_subject.as_str(),
                     serde_json::to_vec(&ban_event_message).unwrap()
                 );
 
             match &result {
                 Ok(()) => {
                     debug!(target: "borealis_banhammer_nats", "Message Producer [JSON bytes vector]: Actual Connection: NATS Connection: {:?}", &nats_connection);
+                    if let Event::WouldBan(_) = ban_event_message {
+                        Measure::inc(Counter::ShadowMessagesSent);
+                    } else {
+                        Measure::inc(Counter::MessagesSent);
+                    }
                     drop(result);
                     drop(nats_connection);
                     break;
@@ -152,7 +395,6 @@ async fn message_producer(
                         .unwrap_or_else(|error|
                             error!(target: "borealis_banhammer_nats", "Message Producer [JSON bytes vector]: New Connection Request: NATS Connection with CID {} event send error: {:?}", nats_connection.cid, error)
                         );
-                    drop(error);
                     drop(result);
                     drop(nats_connection);
                     tokio::time::sleep(core::time::Duration::from_millis(500)).await;
@@ -203,7 +445,13 @@ async fn config_message_consumer(
                     match message {
                         Ok(msg) => {
                             info!(target: "borealis_banhammer_nats", "Received message:\n{}", &msg);
-                            let config_message = serde_json::from_slice::<BanhammerConfigMessage>(msg.data.as_ref()).unwrap();
+                            let config_message = match serde_json::from_slice::<BanhammerConfigMessage>(msg.data.as_ref()) {
+                                Ok(config_message) => config_message,
+                                Err(error) => {
+                                    error!(target: "borealis_banhammer_nats", "Message Consumer [Configuration Message]: Configuration message decoding error: {:?}", error);
+                                    continue;
+                                }
+                            };
                             // Print `BanhammerConfigMessage` data structure for debug purposes.
                             if let Some(VerbosityLevel::WithNATSMessagesDump) = verbosity_level {
                                 debug!(
@@ -249,7 +497,6 @@ async fn config_message_consumer(
                             error!(target: "borealis_banhammer_nats", "Message Consumer [Configuration Messages]: New Connection Request: NATS Connection with CID {} event send error: {:?}", nats_connection.cid, error)
                         );
                     error_rate = 0;
-                    drop(error);
                     drop(subscription);
                     drop(nats_connection);
                     tokio::time::sleep(core::time::Duration::from_millis(500)).await;
@@ -291,13 +538,19 @@ async fn relayer_message_consumer(
                     match message {
                         Ok(msg) => {
                             info!(target: "borealis_banhammer_nats", "Received message:\n{}", &msg);
-                            let relayer_message = serde_json::from_slice::<RelayerMessage>(msg.data.as_ref()).unwrap();
+                            let relayer_message = match serde_json::from_slice::<RelayerMessage>(msg.data.as_ref()) {
+                                Ok(relayer_message) => relayer_message,
+                                Err(error) => {
+                                    error!(target: "borealis_banhammer_nats", "Message Consumer [Relayer Message]: Relayer message decoding error: {:?}", error);
+                                    continue;
+                                }
+                            };
                             // Print `RelayerMessage` data structure for debug purposes.
                             if let Some(VerbosityLevel::WithNATSMessagesDump) = verbosity_level {
                                 debug!(
                                     target: "borealis_banhammer_nats",
-                                    "Received relayer message: {}\n",
-                                    serde_json::to_string_pretty(&relayer_message).unwrap()
+                                    "Received relayer message: {:#?}\n",
+                                    &relayer_message
                                 );
                             };
                             relayer_message_stream_tx
@@ -337,7 +590,6 @@ async fn relayer_message_consumer(
                             error!(target: "borealis_banhammer_nats", "Message Consumer [Relayer Messages]: New Connection Request: NATS Connection with CID {} event send error: {:?}", nats_connection.cid, error)
                         );
                     error_rate = 0;
-                    drop(error);
                     drop(subscription);
                     drop(nats_connection);
                     tokio::time::sleep(core::time::Duration::from_millis(500)).await;
@@ -425,7 +677,6 @@ async fn ethcall_message_consumer(
                             error!(target: "borealis_banhammer_nats", "Message Consumer [Eth_Call Messages]: New Connection Request: NATS Connection with CID {} event send error: {:?}", nats_connection.cid, error)
                         );
                     error_rate = 0;
-                    drop(error);
                     drop(subscription);
                     drop(nats_connection);
                     tokio::time::sleep(core::time::Duration::from_millis(500)).await;
@@ -474,14 +725,12 @@ where
                                         .unwrap_or_else(|error|
                                             error!(target: "borealis_banhammer_nats", "Events Processing: Actual Connection: NATS Connection with CID {} send error: {:?}", nats_connection_actual.cid, error)
                                         );
-                                    drop(nats_connection_actual);
                                     drop(result);
                                     drop(nats_connection);
                                     break;
                                 }
                                 Err(error) => {
                                     error!(target: "borealis_banhammer_nats", "Events Processing: NATS connection error or wrong credentials: {:?}", error);
-                                    drop(error);
                                     drop(result);
                                     drop(nats_connection);
                                     tokio::time::sleep(core::time::Duration::from_millis(500)).await;
@@ -508,14 +757,12 @@ where
                                         .unwrap_or_else(|error|
                                             error!(target: "borealis_banhammer_nats", "Events Processing: Actual Connection: NATS Connection with CID {} send error: {:?}", nats_connection_actual.cid, error)
                                         );
-                                    drop(nats_connection_actual);
                                     drop(result);
                                     drop(nats_connection);
                                     break;
                                 }
                                 Err(error) => {
                                     error!(target: "borealis_banhammer_nats", "Events Processing: NATS connection error or wrong credentials: {:?}", error);
-                                    drop(error);
                                     drop(result);
                                     drop(nats_connection);
                                     tokio::time::sleep(core::time::Duration::from_millis(500)).await;
@@ -542,14 +789,12 @@ where
                                         .unwrap_or_else(|error|
                                             error!(target: "borealis_banhammer_nats", "Events Processing: Actual Connection: NATS Connection with CID {} send error: {:?}", nats_connection_actual.cid, error)
                                         );
-                                    drop(nats_connection_actual);
                                     drop(result);
                                     drop(nats_connection);
                                     break;
                                 }
                                 Err(error) => {
                                     error!(target: "borealis_banhammer_nats", "Events Processing: NATS connection error or wrong credentials: {:?}", error);
-                                    drop(error);
                                     drop(result);
                                     drop(nats_connection);
                                     tokio::time::sleep(core::time::Duration::from_millis(500)).await;
@@ -576,14 +821,12 @@ where
                                         .unwrap_or_else(|error|
                                             error!(target: "borealis_banhammer_nats", "Events Processing: Actual Connection: NATS Connection with CID {} send error: {:?}", nats_connection_actual.cid, error)
                                         );
-                                    drop(nats_connection_actual);
                                     drop(result);
                                     drop(nats_connection);
                                     break;
                                 }
                                 Err(error) => {
                                     error!(target: "borealis_banhammer_nats", "Events Processing: NATS connection error or wrong credentials: {:?}", error);
-                                    drop(error);
                                     drop(result);
                                     drop(nats_connection);
                                     tokio::time::sleep(core::time::Duration::from_millis(500)).await;
@@ -622,7 +865,6 @@ where
                         .unwrap_or_else(|error|
                             error!(target: "borealis_banhammer_nats", "Events Processing Check: New Connection Request: NATS Connection with CID {} event send error: {:?}", nats_connection.cid, error)
                         );
-                    drop(error);
                     drop(result);
                     drop(nats_connection);
                     std::thread::sleep(core::time::Duration::from_millis(500));
@@ -1031,16 +1273,26 @@ fn main() -> Result<(), Error> {
             .home_dir
             .unwrap_or(std::path::PathBuf::from("./.borealis-banhammer"));
 
+        // Banhammer's leaky buckets configuration
+        let ban_manager_config = load_config(&home_dir)?;
+        // Candidate leaky buckets configuration for shadow (dry-run) evaluation
+        let shadow_ban_manager_config = load_shadow_config(&home_dir)?;
+        // API tokens registry for token tiers
+        let token_registry = load_token_registry(&home_dir)?;
+
         // Channels for receiving relayer, buckets configuration and eth_call messages,
         // and channel for transmitting the Banhammer's ban event messages,
         // to/from NATS subjects
         let (ban_event_stream_tx, ban_event_stream_rx) = 
             mpsc::channel::<BanhammerBanEventMessage>(1000);
+        let (shadow_event_stream_tx, shadow_event_stream_rx) = 
+            mpsc::channel::<BanhammerBanEventMessage>(1000);
         let (config_message_stream_tx, config_message_stream_rx) = 
             mpsc::channel::<BanhammerConfigMessage>(1000);
         let (relayer_message_stream_tx, relayer_message_stream_rx) = 
             mpsc::channel::<RelayerMessage>(1000);
-        let (ethcall_message_stream_tx, ethcall_message_stream_rx) = 
+        // TODO: eth_call messages aren't processed by Banhammer yet
+        let (ethcall_message_stream_tx, _ethcall_message_stream_rx) = 
             mpsc::channel::<EthCallMessage>(1000);
 
         // Channels for sending/receiving NATS connection's events
@@ -1061,6 +1313,12 @@ fn main() -> Result<(), Error> {
         let connection_event_tx_for_bh_msg_pub = connection_event_tx.clone();
         let actual_connection_rx_for_bh_msg_pub = actual_connection_tx.subscribe();
 
+        // Channel rx/tx pair clones for NATS connection events sent to event processing,
+        // and receiving actual NATS connection, in a Banhammer's would-ban event messages producer
+        // Needed as local variables due to the usage in generator with move semantics on a call site
+        let connection_event_tx_for_bh_shadow_msg_pub = connection_event_tx.clone();
+        let actual_connection_rx_for_bh_shadow_msg_pub = actual_connection_tx.subscribe();
+
         // Channel rx/tx pair clones for NATS connection events sent to event processing,
         // and receiving actual NATS connection, in a Banhammer's buckets configuration messages consumer
         // Needed as local variables due to the usage in generator with move semantics on a call site
@@ -1090,13 +1348,11 @@ fn main() -> Result<(), Error> {
                                 .unwrap_or_else(|error|
                                     error!(target: "borealis_banhammer_nats", "Main(): Connect with extended options: NATS Connection with CID {} send error: {:?}", nats_connection.cid, error)
                                 );
-                            drop(nats_connection);
                             drop(result);
                             break;
                         }
                         Err(error) => {
                             error!(target: "borealis_banhammer_nats", "Main(): Connect with extended options: NATS connection error or wrong credentials: {:?}", error);
-                            drop(error);
                             drop(result);
                             std::thread::sleep(core::time::Duration::from_millis(500));
                             continue;
@@ -1110,7 +1366,7 @@ fn main() -> Result<(), Error> {
             SubCommand::Check(context) => {
 
                 let events_processing_rt = actix::System::with_tokio_rt(||
-                    events_processing_rt(opts.verbose.clone())
+                    events_processing_rt(opts.verbose)
                     .expect("Main(): Check(): Run-time error returned while creating Banhammer's custom Tokio run-time for Actix")
                 );
 
@@ -1163,16 +1419,19 @@ fn main() -> Result<(), Error> {
             SubCommand::Run(context) => {
 
                 let messages_processing_rt = actix::System::with_tokio_rt(||
-                    messages_processing_rt(opts.verbose.clone())
+                    messages_processing_rt(opts.verbose)
                     .expect("Main(): Run(): Run-time error returned while creating Banhammer's custom Tokio run-time for Actix")
                 );
 
                 // Needed as local variables due to the usage in generator with move semantics on a call site
                 let events_processing_context = context.clone();
-                let message_producer_context = context.clone();
+                let message_producer_subject = context.tx_subject.clone();
+                let shadow_message_producer_subject = context.shadow_tx_subject.clone();
                 let config_message_consumer_context = context.clone();
                 let relayer_message_consumer_context = context.clone();
                 let ethcall_message_consumer_context = context.clone();
+                let ban_manager_shards = context.shards
+                    .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |shards| shards.get()));
 
                 // NATS messages processing run-time tasks
                 messages_processing_rt.block_on(async move {
@@ -1212,13 +1471,42 @@ fn main() -> Result<(), Error> {
                         connection_event_sender.clone(),
                     );
 
+                    // Banhammer's leaky buckets processing of relayer messages
+                    actix::spawn(async move {
+                        ban_manager(
+                            relayer_message_stream_rx,
+                            config_message_stream_rx,
+                            ban_event_stream_tx,
+                            shadow_event_stream_tx,
+                            sharded_ban_manager(ban_manager_config, &token_registry, ban_manager_shards, LiveMetrics::shared()),
+                            // Candidate's metrics are written to shadow series, live dashboards and alerts aren't affected
+                            shadow_ban_manager_config.map(|shadow_ban_manager_config|
+                                sharded_ban_manager(shadow_ban_manager_config, &token_registry, ban_manager_shards, ShadowMetrics::shared())
+                            ),
+                            home_dir,
+                        )
+                        .await;
+                    });
+
                     // Banhammer's ban event messages producer
                     actix::spawn(async move {
                         message_producer(
                             ban_event_stream_rx,
                             actual_connection_rx_for_bh_msg_pub,
                             connection_event_tx_for_bh_msg_pub,
-                            message_producer_context,
+                            message_producer_subject,
+                            opts.verbose,
+                        )
+                        .await;
+                    });
+
+                    // Banhammer's would-ban event messages producer for shadow (dry-run) evaluation
+                    actix::spawn(async move {
+                        message_producer(
+                            shadow_event_stream_rx,
+                            actual_connection_rx_for_bh_shadow_msg_pub,
+                            connection_event_tx_for_bh_shadow_msg_pub,
+                            shadow_message_producer_subject,
                             opts.verbose,
                         )
                         .await;